@@ -34,7 +34,6 @@ fn main() {
     (0..2).for_each(|_| short_scoped());
     (0..2).for_each(|_| short());
 
-    metrics.for_each_histogram(|span_name, h| {
-        println!("{} -> {:.2}ms", span_name, h.mean() / 1_000_000.0);
-    });
+    metrics.flush();
+    println!("{}", metrics);
 }
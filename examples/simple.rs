@@ -43,7 +43,6 @@ fn main() {
     t1.join().unwrap();
     t2.join().unwrap();
 
-    metrics.for_each_histogram(|span_name, h| {
-        println!("{} -> {:.2}ms", span_name, h.mean() / 1_000_000.0);
-    });
+    metrics.flush();
+    println!("{}", metrics);
 }
@@ -11,39 +11,91 @@
 extern crate proc_macro;
 
 use crate::proc_macro::TokenStream;
+#[cfg(not(feature = "disable"))]
 use quote::quote;
+#[cfg(not(feature = "disable"))]
 use syn::{parse_macro_input, parse_quote, AttributeArgs, Item, LitStr, Meta, NestedMeta};
 
+/// Instruments a function with a `game_metrics::Span` covering its body.
+///
+/// The span is named after the fully qualified path of the function, e.g.
+/// `my_game::physics::update`, `my_game::physics::World::update` or
+/// `<my_game::physics::World as my_game::System>::update`, so functions of the same name in
+/// different modules or impls are told apart. The path is taken from the `std::any::type_name` of
+/// a function item nested in the instrumented function. Its `game_metrics::Callsite` records the
+/// file, line and module of the function.
+///
+/// Accepts `name = "..."` to override the span name, `category = "..."` to place the span in a
+/// runtime filterable `game_metrics::Category` and `sample = N` to collect only one in every `N`
+/// calls.
+///
+/// # Panics
+/// Panics if applied to anything other than a function.
 #[cfg(not(feature = "disable"))]
 #[proc_macro_attribute]
 pub fn instrument(attr: TokenStream, input: TokenStream) -> TokenStream {
     let attrs = parse_macro_input!(attr as AttributeArgs);
 
-    let name = attrs
-        .iter()
-        .find_map(|attr| {
-            if let NestedMeta::Meta(meta) = attr {
-                if let Meta::NameValue(kv) = meta {
-                    if kv.path.is_ident("name") {
-                        if let syn::Lit::Str(s) = &kv.lit {
-                            return Some(s.clone());
-                        }
-                    }
-                }
-            }
-            None
-        });
+    let name = find_str_arg(&attrs, "name");
+    let category = find_str_arg(&attrs, "category");
+    let sample = attrs.iter().find_map(|attr| match attr {
+        NestedMeta::Meta(Meta::NameValue(kv)) if kv.path.is_ident("sample") => match &kv.lit {
+            syn::Lit::Int(n) => Some(n.clone()),
+            _ => None,
+        },
+        _ => None,
+    });
 
     let inner = parse_macro_input!(input as Item);
     match inner {
         Item::Fn(mut f) => {
-            let name = name.unwrap_or(LitStr::new(&f.sig.ident.to_string(), proc_macro2::Span::call_site()));
+            let ident = LitStr::new(&f.sig.ident.to_string(), f.sig.ident.span());
+            let (callsite, callsite_ref) = if let Some(name) = name {
+                (
+                    quote! { game_metrics::Callsite::new(#name, module_path!(), file!(), line!()) },
+                    quote! { &__CALLSITE },
+                )
+            } else {
+                (
+                    quote! {
+                        game_metrics::Callsite::function(#ident, module_path!(), file!(), line!())
+                    },
+                    quote! {
+                        {
+                            fn __callsite_fn() {}
+                            __CALLSITE.in_fn(__callsite_fn)
+                        }
+                    },
+                )
+            };
 
             let block = f.block;
+            let category = if let Some(category) = category {
+                quote! {
+                    static __CATEGORY: game_metrics::Category =
+                        game_metrics::Category::new(#category);
+                    let __category = Some(&__CATEGORY);
+                }
+            } else {
+                quote! { let __category = None; }
+            };
+            let sampler = if let Some(sample) = sample {
+                quote! {
+                    static __SAMPLER: game_metrics::Sampler =
+                        game_metrics::Sampler::new(game_metrics::SamplePolicy::OneIn(#sample));
+                    let __sampler = Some(&__SAMPLER);
+                }
+            } else {
+                quote! { let __sampler = None; }
+            };
             f.block = Box::new(parse_quote! {
                 {
                     {
-                        let __span = game_metrics::Span::new(#name);
+                        static __CALLSITE: game_metrics::Callsite = #callsite;
+                        #category
+                        #sampler
+                        let __span =
+                            game_metrics::Span::with_callsite(#callsite_ref, __category, __sampler);
                         #block
                     }
                 }
@@ -54,9 +106,21 @@ pub fn instrument(attr: TokenStream, input: TokenStream) -> TokenStream {
     }
 }
 
+#[cfg(not(feature = "disable"))]
+fn find_str_arg(attrs: &[NestedMeta], key: &str) -> Option<LitStr> {
+    attrs.iter().find_map(|attr| match attr {
+        NestedMeta::Meta(Meta::NameValue(kv)) if kv.path.is_ident(key) => match &kv.lit {
+            syn::Lit::Str(s) => Some(s.clone()),
+            _ => None,
+        },
+        _ => None,
+    })
+}
+
+/// With the `disable` feature, `#[instrument]` leaves the function untouched.
 #[cfg(feature = "disable")]
 #[proc_macro_attribute]
-pub fn instrument(attr: TokenStream, input: TokenStream) -> TokenStream {
-    input.into()
+pub fn instrument(_attr: TokenStream, input: TokenStream) -> TokenStream {
+    input
 }
 
@@ -0,0 +1,197 @@
+//! Span based allocation tracking.
+//!
+//! With the `alloc` feature, installing `TrackingAllocator` as the global allocator attributes
+//! the number and size of allocations made on each thread to the innermost active `Span`, and
+//! `Metrics::allocations` reports the totals alongside the span timings. Allocations made by
+//! nested spans are attributed to those spans only, and those made by this crate itself, e.g.
+//! to send events, are not counted.
+
+#[cfg(feature = "alloc")]
+use std::{
+    alloc::{GlobalAlloc, Layout},
+    cell::Cell,
+};
+
+/// A count of allocations and the bytes they requested.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Allocations {
+    /// The number of fresh allocations. Reallocations are not counted.
+    pub count: u64,
+    /// The bytes requested by fresh allocations, plus the bytes reallocations grew by.
+    pub bytes: u64,
+}
+#[cfg(all(feature = "alloc", not(feature = "disable")))]
+impl Allocations {
+    fn saturating_sub(self, other: Self) -> Self {
+        Self {
+            count: self.count.saturating_sub(other.count),
+            bytes: self.bytes.saturating_sub(other.bytes),
+        }
+    }
+}
+impl std::ops::Add for Allocations {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self {
+            count: self.count + other.count,
+            bytes: self.bytes + other.bytes,
+        }
+    }
+}
+impl std::ops::AddAssign for Allocations {
+    fn add_assign(&mut self, other: Self) {
+        *self = *self + other;
+    }
+}
+
+#[cfg(feature = "alloc")]
+thread_local! {
+    static ALLOCATED: Cell<Allocations> = const { Cell::new(Allocations { count: 0, bytes: 0 }) };
+}
+
+#[cfg(feature = "alloc")]
+thread_local! {
+    /// Set while this crate does its own work, so its allocations aren't attributed to spans.
+    static UNTRACKED: Cell<bool> = const { Cell::new(false) };
+}
+
+#[cfg(all(feature = "alloc", not(feature = "disable")))]
+thread_local! {
+    static CHILDREN: Cell<Allocations> = const { Cell::new(Allocations { count: 0, bytes: 0 }) };
+}
+
+/// A `GlobalAlloc` wrapper which counts allocations per thread, so they can be attributed to the
+/// active `Span`.
+///
+/// # Example
+/// ```
+/// use game_metrics::{scope, Metrics, TrackingAllocator};
+/// use std::alloc::System;
+///
+/// #[global_allocator]
+/// static ALLOCATOR: TrackingAllocator<System> = TrackingAllocator::new(System);
+///
+/// fn load() {
+///     scope!("load");
+///     let buffer = vec![0u8; 1024];
+///     drop(buffer);
+/// }
+///
+/// let metrics = Metrics::new(1);
+/// load();
+/// metrics.flush();
+///
+/// let allocations = metrics.allocations("load").unwrap();
+/// assert_eq!(allocations.count, 1);
+/// assert_eq!(allocations.bytes, 1024);
+/// ```
+#[cfg(feature = "alloc")]
+pub struct TrackingAllocator<A: GlobalAlloc> {
+    inner: A,
+}
+#[cfg(feature = "alloc")]
+impl<A: GlobalAlloc> TrackingAllocator<A> {
+    pub const fn new(inner: A) -> Self {
+        Self { inner }
+    }
+}
+#[cfg(feature = "alloc")]
+unsafe impl<A: GlobalAlloc> GlobalAlloc for TrackingAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        record(1, layout.size());
+        self.inner.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.inner.dealloc(ptr, layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        record(1, layout.size());
+        self.inner.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        record(0, new_size.saturating_sub(layout.size()));
+        self.inner.realloc(ptr, layout, new_size)
+    }
+}
+
+#[cfg(feature = "alloc")]
+fn record(count: u64, bytes: usize) {
+    if UNTRACKED.try_with(Cell::get).unwrap_or(true) {
+        return;
+    }
+
+    ALLOCATED
+        .try_with(|allocated| {
+            let mut total = allocated.get();
+            total.count += count;
+            total.bytes += bytes as u64;
+            allocated.set(total);
+        })
+        .ok();
+}
+
+/// Restores the previous tracking state of the thread when dropped.
+#[cfg(all(feature = "alloc", not(feature = "disable")))]
+struct Untracked(bool);
+#[cfg(all(feature = "alloc", not(feature = "disable")))]
+impl Drop for Untracked {
+    fn drop(&mut self) {
+        UNTRACKED.try_with(|untracked| untracked.set(self.0)).ok();
+    }
+}
+
+/// Runs `f` without counting the allocations it makes, for the crate's own bookkeeping.
+#[cfg(all(feature = "alloc", not(feature = "disable")))]
+pub(crate) fn untracked<R>(f: impl FnOnce() -> R) -> R {
+    let previous = UNTRACKED.try_with(|untracked| untracked.replace(true));
+    let _restore = Untracked(previous.unwrap_or(true));
+    f()
+}
+
+#[cfg(not(all(feature = "alloc", not(feature = "disable"))))]
+#[inline(always)]
+pub(crate) fn untracked<R>(f: impl FnOnce() -> R) -> R {
+    f()
+}
+
+/// The allocation state saved by a `Span` on entry.
+#[cfg(all(feature = "alloc", not(feature = "disable")))]
+pub(crate) struct AllocScope {
+    start: Allocations,
+    parent_children: Allocations,
+}
+
+#[cfg(all(feature = "alloc", not(feature = "disable")))]
+pub(crate) fn enter() -> AllocScope {
+    AllocScope {
+        start: ALLOCATED.with(Cell::get),
+        parent_children: CHILDREN.with(|children| children.replace(Allocations::default())),
+    }
+}
+
+/// Returns the allocations made directly within the span, excluding those of nested spans.
+#[cfg(all(feature = "alloc", not(feature = "disable")))]
+pub(crate) fn exit(scope: &AllocScope) -> Allocations {
+    let inclusive = ALLOCATED.with(Cell::get).saturating_sub(scope.start);
+    let children = CHILDREN.with(|children| children.replace(scope.parent_children + inclusive));
+    inclusive.saturating_sub(children)
+}
+
+#[cfg(not(any(feature = "alloc", feature = "disable")))]
+pub(crate) struct AllocScope;
+
+#[cfg(not(any(feature = "alloc", feature = "disable")))]
+#[inline(always)]
+pub(crate) fn enter() -> AllocScope {
+    AllocScope
+}
+
+#[cfg(not(any(feature = "alloc", feature = "disable")))]
+#[inline(always)]
+pub(crate) fn exit(_scope: &AllocScope) -> Allocations {
+    Allocations::default()
+}
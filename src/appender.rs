@@ -0,0 +1,157 @@
+use crate::logging::LogRecord;
+use std::{
+    fmt::Write as _,
+    io::{self, Write},
+    time::UNIX_EPOCH,
+};
+
+/// An output for log records. Appenders are owned by the logger `Settings` and are invoked from
+/// the logging worker, so they must be `Send`.
+pub trait Appender: Send {
+    /// Write a single record.
+    fn append(&mut self, record: &LogRecord);
+
+    /// Flush any buffered output.
+    fn flush(&mut self) {}
+}
+
+/// Writes the record message, followed by any structured fields, to stdout.
+pub struct ConsoleAppender;
+impl Appender for ConsoleAppender {
+    fn append(&mut self, record: &LogRecord) {
+        let mut line = record.message.clone();
+        for (key, value) in &record.fields {
+            write!(line, " {}={}", key, value).ok();
+        }
+        println!("{}", line);
+    }
+}
+
+/// Writes each record as a single JSON object per line ([JSON Lines](https://jsonlines.org/)),
+/// for consumption by log aggregation and crash triage tooling.
+///
+/// Each line contains `timestamp` (milliseconds since the unix epoch), `level`, `target`,
+/// `thread`, `module_path`, `file`, `line`, `span`, `frame`, `message` and a `fields` object
+/// holding the structured key/value pairs of the record.
+///
+/// # Example
+/// ```
+/// use game_metrics::{Appender, JsonAppender, LogLevel, LogRecord};
+///
+/// let mut appender = JsonAppender::new(Vec::new());
+/// appender.append(&LogRecord {
+///     timestamp: std::time::UNIX_EPOCH,
+///     target: "game::net".to_owned(),
+///     level: LogLevel::Warn,
+///     message: "packet \"dropped\"".to_owned(),
+///     module_path: None,
+///     file: None,
+///     line: None,
+///     thread: "main".to_owned(),
+///     fields: vec![("peer".to_owned(), "7".to_owned())],
+///     span_path: Some("frame>net".to_owned()),
+///     frame: Some(12),
+/// });
+///
+/// let json = String::from_utf8(appender.into_inner()).unwrap();
+/// assert_eq!(
+///     json,
+///     "{\"timestamp\":0,\"level\":\"WARN\",\"target\":\"game::net\",\"thread\":\"main\",\
+///      \"module_path\":null,\"file\":null,\"line\":null,\"span\":\"frame>net\",\"frame\":12,\
+///      \"message\":\"packet \\\"dropped\\\"\",\
+///      \"fields\":{\"peer\":\"7\"}}\n"
+/// );
+/// ```
+pub struct JsonAppender<W: Write + Send> {
+    writer: W,
+}
+impl<W: Write + Send> JsonAppender<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+
+    /// Returns the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    fn write_record(&mut self, record: &LogRecord) -> io::Result<()> {
+        let timestamp = record
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis());
+
+        let mut line = String::with_capacity(256);
+        write!(line, "{{\"timestamp\":{},\"level\":", timestamp).ok();
+        push_json_str(&mut line, record.level.as_str());
+        line.push_str(",\"target\":");
+        push_json_str(&mut line, &record.target);
+        line.push_str(",\"thread\":");
+        push_json_str(&mut line, &record.thread);
+        line.push_str(",\"module_path\":");
+        push_json_opt_str(&mut line, record.module_path.as_deref());
+        line.push_str(",\"file\":");
+        push_json_opt_str(&mut line, record.file.as_deref());
+        line.push_str(",\"line\":");
+        match record.line {
+            Some(n) => write!(line, "{}", n).ok(),
+            None => write!(line, "null").ok(),
+        };
+        line.push_str(",\"span\":");
+        push_json_opt_str(&mut line, record.span_path.as_deref());
+        line.push_str(",\"frame\":");
+        match record.frame {
+            Some(n) => write!(line, "{}", n).ok(),
+            None => write!(line, "null").ok(),
+        };
+        line.push_str(",\"message\":");
+        push_json_str(&mut line, &record.message);
+        line.push_str(",\"fields\":{");
+        for (i, (key, value)) in record.fields.iter().enumerate() {
+            if i > 0 {
+                line.push(',');
+            }
+            push_json_str(&mut line, key);
+            line.push(':');
+            push_json_str(&mut line, value);
+        }
+        line.push_str("}}\n");
+
+        self.writer.write_all(line.as_bytes())
+    }
+}
+impl<W: Write + Send> Appender for JsonAppender<W> {
+    fn append(&mut self, record: &LogRecord) {
+        self.write_record(record)
+            .unwrap_or_else(|e| println!("Failed to write log record: {:?}", e));
+    }
+
+    fn flush(&mut self) {
+        self.writer.flush().ok();
+    }
+}
+
+fn push_json_opt_str(out: &mut String, s: Option<&str>) {
+    match s {
+        Some(s) => push_json_str(out, s),
+        None => out.push_str("null"),
+    }
+}
+
+fn push_json_str(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                write!(out, "\\u{:04x}", c as u32).ok();
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
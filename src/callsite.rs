@@ -0,0 +1,283 @@
+//! Static span call sites.
+//!
+//! `scope!` and `#[instrument]` declare a `static Callsite` per call site, which is registered
+//! the first time it is entered and assigned a small integer `CallsiteId`. Spans send only this
+//! id, and `Metrics` keeps its span data in a `Vec` indexed by it. The name and source location
+//! of each call site can be looked up by id with `callsite`, e.g. by exporters.
+//!
+//! Spans created by name at runtime, through `Span::new` or `Metrics::start_span`, share one
+//! call site per name without a source location. Each thread caches the call sites of the names it
+//! has used, so only the first use of a name on a thread takes the global lock.
+//!
+//! # Example
+//! ```
+//! use game_metrics::{scope, Metrics};
+//!
+//! fn update() {
+//!     scope!("update");
+//! }
+//!
+//! let metrics = Metrics::new(1);
+//! update();
+//!
+//! metrics.flush();
+//! metrics.for_each_callsite_histogram(|callsite, _| {
+//!     assert_eq!(callsite.name(), "update");
+//!     assert_eq!(callsite.file(), file!());
+//!     assert!(callsite.line() > 0);
+//! });
+//! ```
+#![cfg_attr(feature = "disable", allow(dead_code))]
+
+use crate::sampling::Sampler;
+use fxhash::FxHashMap;
+use parking_lot::RwLock;
+use std::{
+    ptr,
+    sync::{
+        atomic::{AtomicPtr, AtomicU32, AtomicU64, Ordering},
+        OnceLock,
+    },
+};
+
+lazy_static::lazy_static! {
+    static ref REGISTRY: RwLock<Vec<&'static Callsite>> = RwLock::new(Vec::new());
+    static ref BY_NAME: RwLock<FxHashMap<&'static str, &'static Callsite>> =
+        RwLock::new(FxHashMap::default());
+}
+
+#[cfg(not(feature = "disable"))]
+thread_local! {
+    /// Call sites by the address and length of the names used on this thread.
+    static NAME_CACHE: std::cell::RefCell<FxHashMap<(usize, usize), &'static Callsite>> =
+        std::cell::RefCell::new(FxHashMap::default());
+}
+
+const UNREGISTERED: u32 = u32::MAX;
+
+/// Identifies a registered `Callsite`. Ids are assigned in registration order, starting at 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct CallsiteId(u32);
+impl CallsiteId {
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+
+    pub(crate) fn from_index(index: usize) -> Self {
+        CallsiteId(index as u32)
+    }
+}
+
+/// The name and source location of a span.
+pub struct Callsite {
+    name: &'static str,
+    module_path: &'static str,
+    file: &'static str,
+    line: u32,
+    id: AtomicU32,
+    qualified: OnceLock<&'static str>,
+    sampling_generation: AtomicU64,
+    sampler: AtomicPtr<Sampler>,
+}
+impl Callsite {
+    pub const fn new(
+        name: &'static str,
+        module_path: &'static str,
+        file: &'static str,
+        line: u32,
+    ) -> Self {
+        Self {
+            name,
+            module_path,
+            file,
+            line,
+            id: AtomicU32::new(UNREGISTERED),
+            qualified: OnceLock::new(),
+            sampling_generation: AtomicU64::new(0),
+            sampler: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    /// Creates the call site of the function named `name`, which must be given its full path by
+    /// `Callsite::in_fn` before it is used.
+    pub const fn function(
+        name: &'static str,
+        module_path: &'static str,
+        file: &'static str,
+        line: u32,
+    ) -> Self {
+        Self::new(name, module_path, file, line)
+    }
+
+    /// Names a call site created with `Callsite::function` after the path of `item`, a function
+    /// item nested in the function, returning the call site. Only the first call has an effect,
+    /// and generic arguments are left out of the name, as every instance of a generic function
+    /// shares one call site. This is how `#[instrument]` names spans.
+    ///
+    /// # Example
+    /// ```
+    /// use game_metrics::{instrument, Metrics};
+    ///
+    /// struct World;
+    /// impl World {
+    ///     #[instrument]
+    ///     fn new() -> Self {
+    ///         World
+    ///     }
+    /// }
+    ///
+    /// trait System {
+    ///     fn update(&self);
+    /// }
+    /// impl System for World {
+    ///     #[instrument]
+    ///     fn update(&self) {}
+    /// }
+    ///
+    /// fn main() {
+    ///     let metrics = Metrics::new(1);
+    ///     World::new().update();
+    ///
+    ///     metrics.flush();
+    ///     let new = format!("{}::World::new", module_path!());
+    ///     let update = format!("<{0}::World as {0}::System>::update", module_path!());
+    ///     assert_eq!(metrics.estimated_calls(&new), Some(1));
+    ///     assert_eq!(metrics.estimated_calls(&update), Some(1));
+    /// }
+    /// ```
+    pub fn in_fn<F>(&'static self, _item: F) -> &'static Self {
+        self.qualified
+            .get_or_init(|| function_path(std::any::type_name::<F>()));
+        self
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.qualified.get().copied().unwrap_or(self.name)
+    }
+
+    /// The module the call site is in, or an empty string for spans created by name at runtime.
+    pub fn module_path(&self) -> &'static str {
+        self.module_path
+    }
+
+    /// The file the call site is in, or an empty string for spans created by name at runtime.
+    pub fn file(&self) -> &'static str {
+        self.file
+    }
+
+    /// The line of the call site, or 0 for spans created by name at runtime.
+    pub fn line(&self) -> u32 {
+        self.line
+    }
+
+    /// Returns the id of the call site, registering it on first use.
+    #[inline]
+    pub fn id(&'static self) -> CallsiteId {
+        match self.id.load(Ordering::Acquire) {
+            UNREGISTERED => self.register(),
+            id => CallsiteId(id),
+        }
+    }
+
+    #[cold]
+    fn register(&'static self) -> CallsiteId {
+        let mut registry = REGISTRY.write();
+        let id = self.id.load(Ordering::Acquire);
+        if id != UNREGISTERED {
+            return CallsiteId(id);
+        }
+
+        let id = registry.len() as u32;
+        registry.push(self);
+        self.id.store(id, Ordering::Release);
+        CallsiteId(id)
+    }
+
+    /// Returns the sampler registered for the name of the call site with `set_sampling`. The
+    /// registry is only read again after it changed.
+    #[inline]
+    pub(crate) fn registered_sampler(&self) -> Option<&'static Sampler> {
+        if self.sampling_generation.load(Ordering::Acquire) != crate::sampling::generation() {
+            self.resolve_sampler();
+        }
+        // SAFETY: the pointer is either null or was stored from a `&'static Sampler`.
+        unsafe { self.sampler.load(Ordering::Acquire).as_ref() }
+    }
+
+    #[cold]
+    fn resolve_sampler(&self) {
+        crate::sampling::with_registered(self.name(), |generation, sampler| {
+            let sampler = sampler.map_or(ptr::null_mut(), |s| s as *const Sampler as *mut Sampler);
+            self.sampler.store(sampler, Ordering::Release);
+            self.sampling_generation
+                .store(generation, Ordering::Release);
+        });
+    }
+}
+
+/// Returns the path of the function enclosing the function item with the type name `item`,
+/// without generic arguments and closures, e.g. `<game::World as game::System>::update` for
+/// `<game::World<_> as game::System>::update::{{closure}}::f`.
+fn function_path(item: &str) -> &'static str {
+    let path = item.rsplit_once("::").map_or(item, |(path, _)| path);
+    let mut name = String::with_capacity(path.len());
+    let mut generics = 0;
+    for c in path.replace("::{{closure}}", "").chars() {
+        match c {
+            '<' if generics > 0 || name.ends_with(|c: char| c.is_alphanumeric() || c == '_') => {
+                generics += 1
+            }
+            '>' if generics > 0 => generics -= 1,
+            _ if generics > 0 => {}
+            c => name.push(c),
+        }
+    }
+    Box::leak(name.into_boxed_str())
+}
+
+/// Returns the call site registered with `id`.
+pub fn callsite(id: CallsiteId) -> &'static Callsite {
+    REGISTRY.read()[id.index()]
+}
+
+/// Calls `f` with every registered call site, in id order.
+pub fn for_each_callsite<F>(mut f: F)
+where
+    F: FnMut(CallsiteId, &'static Callsite),
+{
+    REGISTRY
+        .read()
+        .iter()
+        .enumerate()
+        .for_each(|(index, callsite)| f(CallsiteId(index as u32), callsite));
+}
+
+/// Returns the call site shared by spans created with `name` at runtime.
+#[cfg(not(feature = "disable"))]
+pub(crate) fn by_name(name: &'static str) -> &'static Callsite {
+    let key = (name.as_ptr() as usize, name.len());
+    NAME_CACHE
+        .try_with(|cache| {
+            if let Some(callsite) = cache.borrow().get(&key) {
+                return *callsite;
+            }
+            let callsite = register_name(name);
+            cache.borrow_mut().insert(key, callsite);
+            callsite
+        })
+        .unwrap_or_else(|_| register_name(name))
+}
+
+#[cfg(not(feature = "disable"))]
+#[cold]
+fn register_name(name: &'static str) -> &'static Callsite {
+    let existing = BY_NAME.read().get(name).copied();
+    let callsite = match existing {
+        Some(callsite) => callsite,
+        None => *BY_NAME
+            .write()
+            .entry(name)
+            .or_insert_with(|| Box::leak(Box::new(Callsite::new(name, "", "", 0)))),
+    };
+    callsite
+}
@@ -0,0 +1,123 @@
+//! Runtime switches for span collection.
+//!
+//! Spans may be tagged with a `Category`, either with `scope!("name", category = "ai")` or
+//! `#[instrument(category = "ai")]`. Each category can be enabled or disabled at runtime, and
+//! spans in a disabled category skip both the clock read and the channel send. `set_enabled`
+//! switches all span collection on or off at once.
+//!
+//! # Example
+//! ```
+//! use game_metrics::{scope, set_category_enabled, Metrics};
+//!
+//! fn think() {
+//!     scope!("think", category = "ai");
+//! }
+//!
+//! let metrics = Metrics::new(1);
+//!
+//! set_category_enabled("ai", false);
+//! think();
+//! metrics.flush();
+//! metrics.for_each_histogram(|span_name, _| assert_ne!(span_name, "think"));
+//!
+//! set_category_enabled("ai", true);
+//! ```
+
+use parking_lot::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+
+/// The maximum number of distinct categories which can be filtered. Categories registered past
+/// this limit are always enabled.
+pub const MAX_CATEGORIES: usize = 64;
+
+const UNREGISTERED: u32 = u32::MAX;
+const OVERFLOW: u32 = u32::MAX - 1;
+
+static ENABLED: AtomicBool = AtomicBool::new(true);
+static MASK: AtomicU64 = AtomicU64::new(!0);
+
+lazy_static::lazy_static! {
+    static ref REGISTRY: Mutex<Vec<String>> = Mutex::new(Vec::new());
+}
+
+fn register(name: &str) -> u32 {
+    let mut registry = REGISTRY.lock();
+    if let Some(index) = registry.iter().position(|n| n == name) {
+        return index as u32;
+    }
+    if registry.len() >= MAX_CATEGORIES {
+        return OVERFLOW;
+    }
+    registry.push(name.to_owned());
+    (registry.len() - 1) as u32
+}
+
+/// A span category. This is normally declared as a `static` by the `scope!` and `#[instrument]`
+/// macros, so the category name is resolved to a mask bit only once per call site.
+pub struct Category {
+    name: &'static str,
+    index: AtomicU32,
+}
+impl Category {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            index: AtomicU32::new(UNREGISTERED),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Returns true if span collection is enabled globally and for this category.
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        if !is_enabled() {
+            return false;
+        }
+
+        let mut index = self.index.load(Ordering::Relaxed);
+        if index == UNREGISTERED {
+            index = register(self.name);
+            self.index.store(index, Ordering::Relaxed);
+        }
+
+        index == OVERFLOW || MASK.load(Ordering::Relaxed) & (1 << index) != 0
+    }
+}
+
+/// Enables or disables all span collection.
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Returns true if span collection is globally enabled.
+#[inline]
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Enables or disables collection of spans in the category `name`. Categories are enabled by
+/// default.
+pub fn set_category_enabled(name: &str, enabled: bool) {
+    let index = register(name);
+    if index == OVERFLOW {
+        return;
+    }
+
+    if enabled {
+        MASK.fetch_or(1 << index, Ordering::Relaxed);
+    } else {
+        MASK.fetch_and(!(1 << index), Ordering::Relaxed);
+    }
+}
+
+/// Returns true if spans in the category `name` are collected. Categories which were never
+/// registered are enabled, and querying them doesn't register them.
+pub fn is_category_enabled(name: &str) -> bool {
+    match REGISTRY.lock().iter().position(|n| n == name) {
+        Some(index) => MASK.load(Ordering::Relaxed) & (1 << index) != 0,
+        None => true,
+    }
+}
@@ -0,0 +1,125 @@
+//! Clock sources used to timestamp spans and events.
+//!
+//! By default spans are timed with `quanta`, which reads the TSC where available. Any
+//! `ClockSource` can be installed in its place with `set_clock_source`; `MockClock` only moves
+//! when advanced by hand, so tests can assert exact span durations. To keep tests running in
+//! parallel from sharing one clock, give each its own with `Collector::with_clock` or
+//! `MetricsRecorder::with_clock` instead.
+//!
+//! # Example
+//! ```
+//! use game_metrics::{scope, set_clock_source, Metrics, MockClock};
+//! use std::time::Duration;
+//!
+//! let clock = MockClock::default();
+//! set_clock_source(clock.clone());
+//!
+//! let metrics = Metrics::new(3);
+//! {
+//!     scope!("tick");
+//!     clock.advance(Duration::from_millis(2));
+//! }
+//!
+//! metrics.flush();
+//! metrics.for_each_histogram(|_, h| assert!(h.equivalent(h.max(), 2_000_000)));
+//! ```
+
+use parking_lot::RwLock;
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+lazy_static::lazy_static! {
+    static ref DEFAULT: QuantaClock = QuantaClock::default();
+    static ref CUSTOM: RwLock<Option<Box<dyn ClockSource>>> = RwLock::new(None);
+}
+
+static HAS_CUSTOM: AtomicBool = AtomicBool::new(false);
+
+/// A monotonic source of nanosecond timestamps.
+pub trait ClockSource: Send + Sync {
+    /// Returns the current time in nanoseconds, relative to an arbitrary fixed point.
+    fn now(&self) -> u64;
+}
+
+/// A clock backed by `quanta`, using the TSC where available. This is the default.
+#[derive(Default)]
+pub struct QuantaClock(quanta::Clock);
+impl ClockSource for QuantaClock {
+    fn now(&self) -> u64 {
+        self.0.now()
+    }
+}
+
+/// A clock backed by `std::time::Instant`.
+pub struct StdClock {
+    base: Instant,
+}
+impl Default for StdClock {
+    fn default() -> Self {
+        Self {
+            base: Instant::now(),
+        }
+    }
+}
+impl ClockSource for StdClock {
+    fn now(&self) -> u64 {
+        self.base.elapsed().as_nanos() as u64
+    }
+}
+
+/// A clock which only moves when advanced by hand. Clones share the same time, so a test can keep
+/// a handle to a clock it has installed with `set_clock_source`.
+#[derive(Clone, Default)]
+pub struct MockClock {
+    time: Arc<AtomicU64>,
+}
+impl MockClock {
+    /// Moves the clock forward by `duration`.
+    pub fn advance(&self, duration: Duration) {
+        self.time
+            .fetch_add(duration.as_nanos() as u64, Ordering::SeqCst);
+    }
+
+    /// Sets the clock to `nanos`.
+    pub fn set(&self, nanos: u64) {
+        self.time.store(nanos, Ordering::SeqCst);
+    }
+}
+impl ClockSource for MockClock {
+    fn now(&self) -> u64 {
+        self.time.load(Ordering::SeqCst)
+    }
+}
+
+/// Replaces the clock used to time spans and events. Spans which are active when the clock is
+/// swapped will report meaningless durations.
+pub fn set_clock_source<C: ClockSource + 'static>(clock: C) {
+    *CUSTOM.write() = Some(Box::new(clock));
+    HAS_CUSTOM.store(true, Ordering::SeqCst);
+}
+
+/// Restores the default `quanta` clock.
+pub fn reset_clock_source() {
+    HAS_CUSTOM.store(false, Ordering::SeqCst);
+    *CUSTOM.write() = None;
+}
+
+/// Reads the clock of the collector active on this thread, or else the global clock. The default
+/// clock is read without taking any lock.
+#[inline]
+pub(crate) fn now() -> u64 {
+    if let Some(now) = crate::collector::now() {
+        return now;
+    }
+    if HAS_CUSTOM.load(Ordering::Relaxed) {
+        if let Some(clock) = CUSTOM.read().as_ref() {
+            return clock.now();
+        }
+    }
+    DEFAULT.now()
+}
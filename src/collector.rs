@@ -0,0 +1,210 @@
+//! Scoped collection.
+//!
+//! By default every span is sent to the process wide channel read by `Metrics`. `with_collector`
+//! overrides this for the current thread while a closure runs, sending the events of spans,
+//! markers and frames created inside it to a `Collector` instead. This lets tests running in
+//! parallel each make assertions about their own spans. A collector created `with_clock` also
+//! times the spans of its threads with its own clock, leaving the global clock source alone.
+//!
+//! # Example
+//! ```
+//! use game_metrics::{scope, with_collector, Collector};
+//!
+//! fn load() {
+//!     scope!("load");
+//! }
+//!
+//! let handles: Vec<_> = (0..2)
+//!     .map(|i| {
+//!         std::thread::spawn(move || {
+//!             let collector = Collector::new(1);
+//!             with_collector(&collector, || (0..=i).for_each(|_| load()));
+//!             collector.estimated_calls("load")
+//!         })
+//!     })
+//!     .collect();
+//!
+//! let calls: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+//! assert_eq!(calls, vec![Some(1), Some(2)]);
+//! ```
+
+use crate::{
+    allocation::Allocations,
+    callsite::Callsite,
+    clock::ClockSource,
+    metrics::{Data, Event},
+};
+use hdrhistogram::Histogram;
+use parking_lot::Mutex;
+use std::{
+    cell::RefCell,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+thread_local! {
+    static CURRENT: RefCell<Option<Collector>> = const { RefCell::new(None) };
+}
+
+/// The number of threads with an active collector, so `dispatch` can skip the thread local
+/// when there are none.
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+/// The events received by a recording `Collector`, with the index of the thread which sent each.
+type EventLog = Arc<Mutex<Vec<(u64, Event)>>>;
+
+/// Collects the events sent on threads inside `with_collector`. Events are processed as they
+/// are sent, so no flushing is needed.
+#[derive(Clone)]
+pub struct Collector {
+    data: Arc<Mutex<Data>>,
+    /// The raw events received, if recording.
+    events: Option<EventLog>,
+    /// The clock used on threads while this collector is active, if not the global one.
+    clock: Option<Arc<dyn ClockSource>>,
+}
+impl Collector {
+    /// Creates a collector whose histograms keep `sigfig` significant figures.
+    pub fn new(sigfig: u8) -> Self {
+        Self {
+            data: Arc::new(Mutex::new(Data::new(sigfig))),
+            events: None,
+            clock: None,
+        }
+    }
+
+    /// Times spans and events on threads using this collector with `clock` instead of the clock
+    /// installed with `set_clock_source`.
+    pub fn with_clock<C: ClockSource + 'static>(mut self, clock: C) -> Self {
+        self.clock = Some(Arc::new(clock));
+        self
+    }
+
+    /// Creates a collector which also keeps every event it receives.
+    pub(crate) fn recording(sigfig: u8) -> Self {
+        Self {
+            events: Some(Arc::new(Mutex::new(Vec::new()))),
+            ..Self::new(sigfig)
+        }
+    }
+
+    /// Calls `f` with the events received by a recording collector, in the order they were sent.
+    pub(crate) fn with_events<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&[(u64, Event)]) -> R,
+    {
+        match &self.events {
+            Some(events) => f(&events.lock()),
+            None => f(&[]),
+        }
+    }
+
+    fn process(&self, event: Event) {
+        if let Some(events) = &self.events {
+            events
+                .lock()
+                .push((crate::metrics::thread_index(), event.clone()));
+        }
+        self.data.lock().process(event);
+    }
+
+    /// Iterate the histograms collected, like `Metrics::for_each_histogram`.
+    pub fn for_each_histogram<F>(&self, mut f: F)
+    where
+        F: FnMut(&'static str, &Histogram<u64>),
+    {
+        self.for_each_callsite_histogram(|callsite, histogram| f(callsite.name(), histogram))
+    }
+
+    /// Iterate the histograms collected along with the `Callsite` of each.
+    pub fn for_each_callsite_histogram<F>(&self, f: F)
+    where
+        F: FnMut(&'static Callsite, &Histogram<u64>),
+    {
+        self.data.lock().for_each_callsite_histogram(f)
+    }
+
+    /// Returns the estimated number of times the span `span_name` was entered.
+    pub fn estimated_calls(&self, span_name: &str) -> Option<u64> {
+        self.data.lock().estimated_calls(span_name)
+    }
+
+    /// Returns the total allocations made directly within the span `span_name`.
+    pub fn allocations(&self, span_name: &str) -> Option<Allocations> {
+        self.data.lock().allocations(span_name)
+    }
+
+    /// Returns the number of markers named `name` collected.
+    pub fn mark_count(&self, name: &str) -> u64 {
+        self.data.lock().mark_count(name)
+    }
+
+    /// Returns the total of the counter `name` collected, or `None` if it was never incremented.
+    pub fn counter(&self, name: &str) -> Option<u64> {
+        self.data.lock().counter(name)
+    }
+}
+
+/// Runs `f`, sending the events of the current thread to `collector` instead of `Metrics` until
+/// it returns. Calls may be nested; the previous collector is restored afterwards, even if `f`
+/// panics.
+pub fn with_collector<F, R>(collector: &Collector, f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let _restore = install(collector.clone());
+    f()
+}
+
+/// Restores the collector which was active before `install` on drop.
+pub(crate) struct Restore(Option<Collector>);
+impl Drop for Restore {
+    fn drop(&mut self) {
+        let previous = self.0.take();
+        CURRENT.with(|current| *current.borrow_mut() = previous);
+        ACTIVE.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Makes `collector` the active collector of the current thread until the returned guard is
+/// dropped.
+pub(crate) fn install(collector: Collector) -> Restore {
+    ACTIVE.fetch_add(1, Ordering::Relaxed);
+    Restore(CURRENT.with(|current| current.borrow_mut().replace(collector)))
+}
+
+/// Processes `event` with the collector active on this thread, or returns it if there is none.
+#[inline]
+pub(crate) fn dispatch(event: Event) -> Option<Event> {
+    if ACTIVE.load(Ordering::Relaxed) == 0 {
+        return Some(event);
+    }
+
+    let mut event = Some(event);
+    let _ = CURRENT.try_with(|current| {
+        if let Some(collector) = &*current.borrow() {
+            collector.process(event.take().unwrap());
+        }
+    });
+    event
+}
+
+/// Reads the clock of the collector active on this thread, if it has one.
+#[inline]
+pub(crate) fn now() -> Option<u64> {
+    if ACTIVE.load(Ordering::Relaxed) == 0 {
+        return None;
+    }
+
+    CURRENT
+        .try_with(|current| {
+            current
+                .borrow()
+                .as_ref()
+                .and_then(|collector| collector.clock.as_ref().map(|clock| clock.now()))
+        })
+        .ok()
+        .flatten()
+}
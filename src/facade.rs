@@ -0,0 +1,297 @@
+//! A bridge to the `metrics` facade crate.
+//!
+//! With the `facade` feature, `FacadeRecorder` implements the `Recorder` trait of the `metrics`
+//! crate on top of `Metrics`, so libraries instrumented with `counter!`, `gauge!` and
+//! `histogram!` report into the same `Metrics` instance, its report and its event sinks.
+//!
+//! Counters feed `Metrics::count`, gauges `Metrics::plot` and histograms `Metrics::record`.
+//! Labels are appended to the name, as in `packets{kind=ack}`. Histograms described with a time
+//! unit such as `Unit::Seconds` are recorded in nanoseconds, and other histograms are rounded to
+//! whole values. The unit is read when a histogram is first registered, so describe histograms
+//! before recording to them.
+//!
+//! Names are kept for the life of the process, as `Metrics` keys its data by `&'static str`. Once
+//! 4096 distinct names are kept, metrics with new labels report under their name without labels,
+//! and past 8192 names new metrics report under `facade_overflow`. Likewise, once counters,
+//! gauges or histograms are registered under 8192 distinct keys, new keys of that kind share one
+//! handle reporting under `facade_overflow`, so labels of unbounded cardinality can't grow memory
+//! without bound.
+//!
+//! # Example
+//! ```
+//! use game_metrics::{FacadeRecorder, Metrics};
+//! use metrics_facade::{counter, describe_histogram, gauge, histogram, Unit};
+//! use std::time::Duration;
+//!
+//! let metrics = Metrics::new(3);
+//! let recorder = FacadeRecorder::new();
+//!
+//! metrics_facade::with_local_recorder(&recorder, || {
+//!     describe_histogram!("load_time", Unit::Seconds, "The time taken to load a level");
+//!     counter!("packets", "kind" => "ack").increment(3);
+//!     gauge!("entities").set(42.0);
+//!     histogram!("load_time").record(Duration::from_millis(20));
+//! });
+//!
+//! metrics.flush();
+//! assert_eq!(metrics.counter("packets{kind=ack}"), Some(3));
+//! assert_eq!(metrics.plot_value("entities"), Some(42.0));
+//! metrics.for_each_value_histogram(|name, histogram| {
+//!     assert_eq!(name, "load_time");
+//!     assert!(histogram.equivalent(histogram.max(), 20_000_000));
+//! });
+//! ```
+
+use crate::Metrics;
+use fxhash::FxHashMap;
+use metrics_facade::{
+    Counter, CounterFn, Gauge, GaugeFn, Histogram, HistogramFn, Key, KeyName, Metadata, Recorder,
+    SharedString, Unit,
+};
+use parking_lot::RwLock;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, OnceLock,
+};
+
+lazy_static::lazy_static! {
+    static ref NAMES: RwLock<FxHashMap<String, &'static str>> = RwLock::new(FxHashMap::default());
+}
+
+/// The number of distinct names interned before names with new labels fold to their name
+/// without labels.
+const MAX_LABELED_NAMES: usize = 4096;
+
+/// The number of distinct names interned before new names fold to `OVERFLOW_NAME`.
+const MAX_NAMES: usize = 8192;
+
+/// The number of keys of each kind of metric given a handle of their own before new keys share
+/// the overflow handle.
+const MAX_HANDLES: usize = 8192;
+
+/// The name metrics report under once no more names can be interned.
+const OVERFLOW_NAME: &str = "facade_overflow";
+
+/// A `metrics::Recorder` feeding counters, gauges and histograms into `Metrics`.
+///
+/// # Example
+/// ```
+/// use game_metrics::{FacadeRecorder, Metrics};
+/// use metrics_facade::counter;
+///
+/// let metrics = Metrics::new(1);
+/// let recorder = FacadeRecorder::new();
+///
+/// metrics_facade::with_local_recorder(&recorder, || {
+///     for id in 0..10_000 {
+///         counter!("requests", "id" => id.to_string()).increment(1);
+///     }
+/// });
+///
+/// metrics.flush();
+/// assert_eq!(metrics.counter("requests{id=0}"), Some(1));
+/// assert_eq!(metrics.counter("requests"), Some(4096));
+/// assert_eq!(metrics.counter("facade_overflow"), Some(10_000 - 8192));
+/// ```
+#[derive(Default)]
+pub struct FacadeRecorder {
+    counters: Handles<Counter>,
+    gauges: Handles<Gauge>,
+    histograms: Handles<Histogram>,
+    /// The unit each histogram was described with, by name.
+    units: RwLock<FxHashMap<String, Unit>>,
+}
+impl FacadeRecorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+/// Returns the name of `key` with its labels, interned on first use. Past `MAX_LABELED_NAMES`
+/// names, new names fall back to the name without labels, and past `MAX_NAMES` to
+/// `OVERFLOW_NAME`.
+fn name(key: &Key) -> &'static str {
+    let mut name = key.name().to_owned();
+    let labels: Vec<String> = key
+        .labels()
+        .map(|label| format!("{}={}", label.key(), label.value()))
+        .collect();
+    if !labels.is_empty() {
+        name.push('{');
+        name.push_str(&labels.join(","));
+        name.push('}');
+    }
+
+    let limit = if labels.is_empty() {
+        MAX_NAMES
+    } else {
+        MAX_LABELED_NAMES
+    };
+    intern(&name, limit)
+        .or_else(|| intern(key.name(), MAX_NAMES))
+        .unwrap_or(OVERFLOW_NAME)
+}
+
+/// Interns `name`, or returns `None` if it is new and `limit` names are already interned.
+fn intern(name: &str, limit: usize) -> Option<&'static str> {
+    if let Some(name) = NAMES.read().get(name) {
+        return Some(name);
+    }
+
+    let mut names = NAMES.write();
+    if let Some(name) = names.get(name) {
+        return Some(name);
+    }
+    if names.len() >= limit {
+        return None;
+    }
+    let interned: &'static str = Box::leak(name.to_owned().into_boxed_str());
+    names.insert(name.to_owned(), interned);
+    Some(interned)
+}
+
+/// The handles of one kind of metric, by key.
+struct Handles<H> {
+    by_key: RwLock<FxHashMap<Key, H>>,
+    /// The handle shared by new keys once `MAX_HANDLES` keys have one.
+    overflow: OnceLock<H>,
+}
+impl<H> Default for Handles<H> {
+    fn default() -> Self {
+        Self {
+            by_key: RwLock::new(FxHashMap::default()),
+            overflow: OnceLock::new(),
+        }
+    }
+}
+impl<H: Clone> Handles<H> {
+    /// Returns the handle registered for `key`, creating it on first use.
+    fn register<F>(&self, key: &Key, create: F) -> H
+    where
+        F: FnOnce(&'static str) -> H,
+    {
+        {
+            let by_key = self.by_key.read();
+            if let Some(handle) = by_key.get(key) {
+                return handle.clone();
+            }
+            if by_key.len() >= MAX_HANDLES {
+                return self.overflow.get_or_init(|| create(OVERFLOW_NAME)).clone();
+            }
+        }
+
+        let mut by_key = self.by_key.write();
+        if let Some(handle) = by_key.get(key) {
+            return handle.clone();
+        }
+        if by_key.len() >= MAX_HANDLES {
+            return self.overflow.get_or_init(|| create(OVERFLOW_NAME)).clone();
+        }
+        let handle = create(name(key));
+        by_key.insert(key.clone(), handle.clone());
+        handle
+    }
+}
+
+impl Recorder for FacadeRecorder {
+    fn describe_counter(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+
+    fn describe_gauge(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+
+    fn describe_histogram(&self, key: KeyName, unit: Option<Unit>, _description: SharedString) {
+        if let Some(unit) = unit {
+            self.units.write().insert(key.as_str().to_owned(), unit);
+        }
+    }
+
+    fn register_counter(&self, key: &Key, _metadata: &Metadata<'_>) -> Counter {
+        self.counters.register(key, |name| {
+            Counter::from_arc(Arc::new(CounterHandle {
+                name,
+                total: AtomicU64::new(0),
+            }))
+        })
+    }
+
+    fn register_gauge(&self, key: &Key, _metadata: &Metadata<'_>) -> Gauge {
+        self.gauges.register(key, |name| {
+            Gauge::from_arc(Arc::new(GaugeHandle {
+                name,
+                value: AtomicU64::new(0f64.to_bits()),
+            }))
+        })
+    }
+
+    fn register_histogram(&self, key: &Key, _metadata: &Metadata<'_>) -> Histogram {
+        let scale = match self.units.read().get(key.name()) {
+            Some(Unit::Seconds) => 1_000_000_000.0,
+            Some(Unit::Milliseconds) => 1_000_000.0,
+            Some(Unit::Microseconds) => 1_000.0,
+            _ => 1.0,
+        };
+        self.histograms.register(key, |name| {
+            Histogram::from_arc(Arc::new(HistogramHandle { name, scale }))
+        })
+    }
+}
+
+struct CounterHandle {
+    name: &'static str,
+    /// The total of the counter, used to turn absolute values into increments.
+    total: AtomicU64,
+}
+impl CounterFn for CounterHandle {
+    fn increment(&self, value: u64) {
+        self.total.fetch_add(value, Ordering::Relaxed);
+        Metrics::count(self.name, value);
+    }
+
+    fn absolute(&self, value: u64) {
+        let previous = self.total.fetch_max(value, Ordering::Relaxed);
+        if value > previous {
+            Metrics::count(self.name, value - previous);
+        }
+    }
+}
+
+struct GaugeHandle {
+    name: &'static str,
+    /// The bits of the current `f64` value.
+    value: AtomicU64,
+}
+impl GaugeHandle {
+    fn update<F: Fn(f64) -> f64>(&self, f: F) {
+        let previous = self
+            .value
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                Some(f(f64::from_bits(bits)).to_bits())
+            })
+            .unwrap();
+        Metrics::plot(self.name, f(f64::from_bits(previous)));
+    }
+}
+impl GaugeFn for GaugeHandle {
+    fn increment(&self, value: f64) {
+        self.update(|current| current + value);
+    }
+
+    fn decrement(&self, value: f64) {
+        self.update(|current| current - value);
+    }
+
+    fn set(&self, value: f64) {
+        self.update(|_| value);
+    }
+}
+
+struct HistogramHandle {
+    name: &'static str,
+    /// The factor converting recorded values to those stored, e.g. seconds to nanoseconds.
+    scale: f64,
+}
+impl HistogramFn for HistogramHandle {
+    fn record(&self, value: f64) {
+        // Negative values saturate to 0.
+        Metrics::record(self.name, (value * self.scale).round() as u64);
+    }
+}
@@ -0,0 +1,204 @@
+//! Flamegraph export.
+//!
+//! `Metrics` tracks the self time of every distinct stack of spans, per thread, and writes it
+//! in the folded stack format of Brendan Gregg's `flamegraph.pl`, one `outer;inner;innermost
+//! <nanoseconds>` line per stack. The output can be rendered with `flamegraph.pl` or `inferno`,
+//! giving flamegraphs of instrumented code without a sampling profiler.
+//!
+//! The self time of a span is its elapsed time minus that of the spans nested in it. Stacks of
+//! all threads are merged. Only measured time is shown: the calls a sampled span skips are not
+//! recorded, so their time counts as self time of the span they ran in.
+//!
+//! Manual spans from `Metrics::start_span` are folded under the span active on the thread which
+//! started them, with their whole duration as self time, as they overlap rather than nest in
+//! that span.
+//!
+//! `Metrics::write_folded_marks` writes the markers of `mark!` in the same format, with the
+//! number of markers recorded under each stack in place of its self time, e.g.
+//! `frame;update;gc_triggered 3`.
+//!
+//! # Example
+//! ```
+//! use game_metrics::{scope, set_clock_source, Metrics, MockClock};
+//! use std::time::Duration;
+//!
+//! let clock = MockClock::default();
+//! set_clock_source(clock.clone());
+//!
+//! let metrics = Metrics::new(1);
+//! {
+//!     scope!("frame");
+//!     clock.advance(Duration::from_nanos(100));
+//!     {
+//!         scope!("update");
+//!         clock.advance(Duration::from_nanos(250));
+//!     }
+//! }
+//!
+//! metrics.flush();
+//! let mut folded = Vec::new();
+//! metrics.write_folded(&mut folded).unwrap();
+//! assert_eq!(
+//!     String::from_utf8(folded).unwrap(),
+//!     "frame 100\nframe;update 250\n"
+//! );
+//! ```
+#![cfg_attr(feature = "disable", allow(dead_code))]
+
+use crate::callsite::CallsiteId;
+use fxhash::FxHashMap;
+use std::io::{self, Write};
+
+/// A node of the stack tree, one for each distinct stack of call sites.
+struct StackNode {
+    callsite: CallsiteId,
+    parent: Option<usize>,
+    children: FxHashMap<CallsiteId, usize>,
+    /// The total self time of the stack, in nanoseconds.
+    self_time: u64,
+}
+
+/// A span which is active on a thread.
+struct ActiveSpan {
+    node: usize,
+    /// The elapsed time of the spans completed inside this one so far, in nanoseconds.
+    children: u64,
+}
+
+/// Accumulates self time per stack from the ordered span events of each thread.
+#[derive(Default)]
+pub(crate) struct StackTree {
+    nodes: Vec<StackNode>,
+    roots: FxHashMap<CallsiteId, usize>,
+    threads: FxHashMap<u64, Vec<ActiveSpan>>,
+    /// The number of markers recorded by name under each stack, or outside of any span.
+    marks: FxHashMap<(Option<usize>, &'static str), u64>,
+}
+impl StackTree {
+    pub(crate) fn enter(&mut self, thread: u64, callsite: CallsiteId) {
+        let node = self.detached(thread, callsite);
+        self.threads
+            .entry(thread)
+            .or_default()
+            .push(ActiveSpan { node, children: 0 });
+    }
+
+    /// Returns the node of `callsite` under the innermost span of `thread`, without entering
+    /// it. Self time is added to the node with `StackTree::add_self_time`.
+    pub(crate) fn detached(&mut self, thread: u64, callsite: CallsiteId) -> usize {
+        let parent = self
+            .threads
+            .get(&thread)
+            .and_then(|stack| stack.last())
+            .map(|active| active.node);
+        let next = self.nodes.len();
+        let children = match parent {
+            Some(parent) => &mut self.nodes[parent].children,
+            None => &mut self.roots,
+        };
+
+        let node = *children.entry(callsite).or_insert(next);
+        if node == next {
+            self.nodes.push(StackNode {
+                callsite,
+                parent,
+                children: FxHashMap::default(),
+                self_time: 0,
+            });
+        }
+        node
+    }
+
+    pub(crate) fn add_self_time(&mut self, node: usize, nanos: u64) {
+        let node = &mut self.nodes[node];
+        node.self_time = node.self_time.saturating_add(nanos);
+    }
+
+    /// Completes the innermost span of `thread`. Exits which don't match the innermost span,
+    /// such as those of spans entered before `Metrics` was created, are ignored.
+    pub(crate) fn exit(&mut self, thread: u64, callsite: CallsiteId, elapsed: u64) {
+        let stack = match self.threads.get_mut(&thread) {
+            Some(stack) => stack,
+            None => return,
+        };
+        match stack.last() {
+            Some(active) if self.nodes[active.node].callsite == callsite => {}
+            _ => return,
+        }
+
+        let active = stack.pop().unwrap();
+        let self_time = elapsed.saturating_sub(active.children);
+        let node = &mut self.nodes[active.node];
+        node.self_time = node.self_time.saturating_add(self_time);
+
+        if let Some(parent) = stack.last_mut() {
+            parent.children = parent.children.saturating_add(elapsed);
+        }
+    }
+
+    /// Counts a marker recorded under the innermost span of `thread`.
+    pub(crate) fn mark(&mut self, thread: u64, name: &'static str) {
+        let node = self
+            .threads
+            .get(&thread)
+            .and_then(|stack| stack.last())
+            .map(|active| active.node);
+        *self.marks.entry((node, name)).or_insert(0) += 1;
+    }
+
+    /// Writes one line per stack and marker name with the number of markers recorded, sorted
+    /// by stack.
+    pub(crate) fn write_folded_marks<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        let mut lines: Vec<(String, u64)> = self
+            .marks
+            .iter()
+            .map(|((node, name), count)| {
+                let name = name.replace(';', ":");
+                match node {
+                    Some(node) => (format!("{};{}", self.path(*node), name), *count),
+                    None => (name, *count),
+                }
+            })
+            .collect();
+        lines.sort();
+
+        for (path, count) in lines {
+            writeln!(writer, "{} {}", path, count)?;
+        }
+        Ok(())
+    }
+
+    /// Writes one line per stack with a non zero self time, sorted by stack.
+    pub(crate) fn write_folded<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        let mut lines: Vec<(String, u64)> = self
+            .nodes
+            .iter()
+            .enumerate()
+            .filter(|(_, node)| node.self_time > 0)
+            .map(|(index, node)| (self.path(index), node.self_time))
+            .collect();
+        lines.sort();
+
+        for (path, self_time) in lines {
+            writeln!(writer, "{} {}", path, self_time)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the names of the stack ending at `index`, outermost first, joined with `;`.
+    fn path(&self, index: usize) -> String {
+        let mut names = Vec::new();
+        let mut current = Some(index);
+        while let Some(index) = current {
+            let node = &self.nodes[index];
+            names.push(
+                crate::callsite::callsite(node.callsite)
+                    .name()
+                    .replace(';', ":"),
+            );
+            current = node.parent;
+        }
+        names.reverse();
+        names.join(";")
+    }
+}
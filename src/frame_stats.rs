@@ -0,0 +1,182 @@
+//! Frame pacing analysis.
+//!
+//! `FrameStats` is fed one mark per presented frame and keeps a fixed length history of frame
+//! times, from which it reports FPS, 1% and 0.1% lows, frame time variance and stutters. It can
+//! be used standalone by calling `FrameStats::mark`, and `Metrics` keeps one which is fed by
+//! `Metrics::next_frame`.
+//!
+//! # Example
+//! ```
+//! use game_metrics::FrameStats;
+//! use std::time::Duration;
+//!
+//! let mut stats = FrameStats::new(FrameStats::DEFAULT_HISTORY_LEN);
+//! (0..99).for_each(|_| stats.record(Duration::from_millis(10)));
+//! stats.record(Duration::from_millis(50));
+//!
+//! assert_eq!(stats.stutters(), 1);
+//! assert!((stats.low_1_percent() - 20.0).abs() < 0.01);
+//! assert!(stats.fps() < 100.0);
+//! ```
+
+use std::{collections::VecDeque, time::Duration};
+
+/// The number of most recent frames the rolling median for stutter detection is taken over.
+const MEDIAN_WINDOW: usize = 60;
+
+/// The number of frames which must be recorded before stutters are detected.
+const MIN_STUTTER_FRAMES: usize = 8;
+
+/// A rolling history of frame times with the FPS, lows, variance and stutters derived from it.
+pub struct FrameStats {
+    history: VecDeque<u64>,
+    capacity: usize,
+    last_mark: Option<u64>,
+    stutter_threshold: f64,
+    stutters: u64,
+    frames: u64,
+}
+impl FrameStats {
+    /// The number of frames kept by `FrameStats::default` and by `Metrics`, ten seconds at 60
+    /// frames per second.
+    pub const DEFAULT_HISTORY_LEN: usize = 600;
+
+    /// Creates frame stats keeping the frame times of the last `history_len` frames.
+    pub fn new(history_len: usize) -> Self {
+        let capacity = history_len.max(1);
+        Self {
+            history: VecDeque::with_capacity(capacity),
+            capacity,
+            last_mark: None,
+            stutter_threshold: 2.0,
+            stutters: 0,
+            frames: 0,
+        }
+    }
+
+    /// Sets how many times longer than the rolling median a frame must take to count as a
+    /// stutter. Defaults to 2.
+    pub fn set_stutter_threshold(&mut self, threshold: f64) {
+        self.stutter_threshold = threshold;
+    }
+
+    /// Marks a presented frame, recording the time since the previous mark.
+    pub fn mark(&mut self) {
+        let now = crate::clock::now();
+        if let Some(last) = self.last_mark.replace(now) {
+            self.record_nanos(now.saturating_sub(last));
+        }
+    }
+
+    /// Records a frame which took `frame_time`.
+    pub fn record(&mut self, frame_time: Duration) {
+        self.record_nanos(frame_time.as_nanos() as u64);
+    }
+
+    pub(crate) fn record_nanos(&mut self, frame_time: u64) {
+        if self.history.len() >= MIN_STUTTER_FRAMES {
+            let median = self.rolling_median();
+            if frame_time as f64 > median as f64 * self.stutter_threshold {
+                self.stutters += 1;
+            }
+        }
+
+        if self.history.len() == self.capacity {
+            self.history.pop_front();
+        }
+        self.history.push_back(frame_time);
+        self.frames += 1;
+    }
+
+    fn rolling_median(&self) -> u64 {
+        let mut window: Vec<u64> = self
+            .history
+            .iter()
+            .rev()
+            .take(MEDIAN_WINDOW)
+            .copied()
+            .collect();
+        window.sort_unstable();
+        window[window.len() / 2]
+    }
+
+    /// The total number of frames recorded.
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// The number of frames which took more than the stutter threshold times the rolling median
+    /// frame time.
+    pub fn stutters(&self) -> u64 {
+        self.stutters
+    }
+
+    /// The frame times of the history, oldest first, in nanoseconds.
+    pub fn history(&self) -> impl ExactSizeIterator<Item = u64> + '_ {
+        self.history.iter().copied()
+    }
+
+    /// The average frames per second over the history.
+    pub fn fps(&self) -> f64 {
+        fps(self.mean())
+    }
+
+    /// The average frames per second of the slowest 1% of frames in the history.
+    pub fn low_1_percent(&self) -> f64 {
+        self.low(0.01)
+    }
+
+    /// The average frames per second of the slowest 0.1% of frames in the history.
+    pub fn low_0_1_percent(&self) -> f64 {
+        self.low(0.001)
+    }
+
+    fn low(&self, fraction: f64) -> f64 {
+        if self.history.is_empty() {
+            return 0.0;
+        }
+
+        let mut sorted: Vec<u64> = self.history.iter().copied().collect();
+        sorted.sort_unstable_by(|a, b| b.cmp(a));
+
+        let count = ((sorted.len() as f64 * fraction).ceil() as usize).max(1);
+        let total: u64 = sorted.iter().take(count).sum();
+        fps(total as f64 / count as f64)
+    }
+
+    /// The mean frame time over the history, in nanoseconds.
+    pub fn mean(&self) -> f64 {
+        if self.history.is_empty() {
+            return 0.0;
+        }
+        self.history.iter().sum::<u64>() as f64 / self.history.len() as f64
+    }
+
+    /// The variance of the frame times over the history, in nanoseconds squared.
+    pub fn variance(&self) -> f64 {
+        if self.history.is_empty() {
+            return 0.0;
+        }
+
+        let mean = self.mean();
+        self.history
+            .iter()
+            .map(|t| (*t as f64 - mean).powi(2))
+            .sum::<f64>()
+            / self.history.len() as f64
+    }
+}
+impl Default for FrameStats {
+    /// Keeps `FrameStats::DEFAULT_HISTORY_LEN` frames of history.
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_HISTORY_LEN)
+    }
+}
+
+fn fps(frame_time: f64) -> f64 {
+    if frame_time > 0.0 {
+        1_000_000_000.0 / frame_time
+    } else {
+        0.0
+    }
+}
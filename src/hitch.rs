@@ -0,0 +1,288 @@
+//! Hitch capture.
+//!
+//! Aggregate histograms show that a spike happened, but not which spans caused it. With hitch
+//! capture enabled through `Metrics::set_hitch_capture`, `Metrics` records the complete span
+//! timeline of every frame, and keeps the timelines of frames over a wall time threshold, along
+//! with a number of frames preceding them, in a bounded buffer of the worst frames seen.
+//!
+//! # Example
+//! ```
+//! use game_metrics::{scope, set_clock_source, HitchCapture, Metrics, MockClock};
+//! use std::time::Duration;
+//!
+//! let clock = MockClock::default();
+//! set_clock_source(clock.clone());
+//!
+//! let metrics = Metrics::new(1);
+//! metrics.set_hitch_capture(Some(HitchCapture {
+//!     threshold: Duration::from_millis(20),
+//!     ..HitchCapture::default()
+//! }));
+//!
+//! metrics.next_frame();
+//! for frame_time in &[16, 16, 40, 16] {
+//!     {
+//!         scope!("frame");
+//!         clock.advance(Duration::from_millis(*frame_time));
+//!     }
+//!     metrics.next_frame();
+//! }
+//!
+//! metrics.flush();
+//! metrics.for_each_hitch(|hitch| {
+//!     let frame = hitch.timeline.last().unwrap();
+//!     assert_eq!(frame.wall, 40_000_000);
+//!     assert_eq!(frame.spans[0].name, "frame");
+//! });
+//! ```
+#![cfg_attr(feature = "disable", allow(dead_code))]
+
+use crate::{callsite::CallsiteId, metrics::SpanId};
+use std::{collections::VecDeque, time::Duration};
+
+/// Settings for hitch capture.
+///
+/// # Example
+/// ```
+/// use game_metrics::{scope, set_clock_source, HitchCapture, Metrics, MockClock};
+/// use std::time::Duration;
+///
+/// let clock = MockClock::default();
+/// set_clock_source(clock.clone());
+///
+/// let metrics = Metrics::new(1);
+/// metrics.set_hitch_capture(Some(HitchCapture {
+///     threshold: Duration::from_millis(20),
+///     max_frame_events: 10,
+///     ..HitchCapture::default()
+/// }));
+///
+/// metrics.next_frame();
+/// for _ in 0..25 {
+///     scope!("entity");
+///     clock.advance(Duration::from_millis(2));
+/// }
+/// metrics.next_frame();
+///
+/// metrics.flush();
+/// let hitches = metrics.take_hitches();
+/// let frame = hitches[0].timeline.last().unwrap();
+/// assert_eq!(frame.spans.len(), 10);
+/// assert_eq!(frame.dropped, 15);
+/// ```
+#[derive(Debug, Clone)]
+pub struct HitchCapture {
+    /// Frames with a wall time over this threshold are captured.
+    pub threshold: Duration,
+    /// The maximum number of hitches kept. Once full, a new hitch replaces the least severe one
+    /// if it is worse.
+    pub capacity: usize,
+    /// The number of frames preceding a hitch whose timelines are kept with it.
+    pub previous_frames: usize,
+    /// The maximum number of spans, markers and log records recorded per frame. Any more are
+    /// counted in `FrameTimeline::dropped`, so a runaway frame can't grow the timeline without
+    /// limit.
+    pub max_frame_events: usize,
+}
+impl Default for HitchCapture {
+    fn default() -> Self {
+        Self {
+            threshold: Duration::from_millis(33),
+            capacity: 8,
+            previous_frames: 0,
+            max_frame_events: 65_536,
+        }
+    }
+}
+
+/// A single completed span within a frame timeline.
+#[derive(Debug, Clone)]
+pub struct TimelineSpan {
+    pub callsite: CallsiteId,
+    pub name: &'static str,
+    /// The index of the thread the span ran on, as assigned by this crate.
+    pub thread: u64,
+    /// The nesting depth of the span on its thread, 0 for outermost spans.
+    pub depth: usize,
+    /// The clock time the span was entered, in nanoseconds.
+    pub start: u64,
+    /// The time the span took, in nanoseconds.
+    pub elapsed: u64,
+}
+
+/// A manual span started with `Metrics::start_span`, which may have begun in an earlier frame
+/// and on a different thread than it ended on.
+#[derive(Debug, Clone)]
+pub struct AsyncSpan {
+    pub id: SpanId,
+    pub callsite: CallsiteId,
+    pub name: &'static str,
+    /// The innermost `Span` active on the starting thread when the span was started.
+    pub parent: Option<&'static str>,
+    /// The index of the thread the span was started on.
+    pub start_thread: u64,
+    /// The index of the thread the span was ended on.
+    pub end_thread: u64,
+    /// The clock time the span was started, in nanoseconds.
+    pub start: u64,
+    /// The time from start to end, in nanoseconds.
+    pub elapsed: u64,
+}
+
+/// An instant marker recorded with `mark!`.
+#[derive(Debug, Clone)]
+pub struct Marker {
+    pub name: &'static str,
+    /// The index of the thread the marker was recorded on.
+    pub thread: u64,
+    /// The clock time the marker was recorded, in nanoseconds.
+    pub time: u64,
+    pub fields: Vec<(&'static str, String)>,
+}
+
+/// A log record emitted while `LoggerSettings::span_events` is enabled.
+#[derive(Debug, Clone)]
+pub struct TimelineLog {
+    pub level: &'static str,
+    pub target: String,
+    pub message: String,
+    /// The span path active on the emitting thread, e.g. `frame>update>physics`.
+    pub span_path: Option<String>,
+    /// The index of the thread the record was logged on.
+    pub thread: u64,
+    /// The clock time the record was logged, in nanoseconds.
+    pub time: u64,
+}
+
+/// The spans which completed during one frame.
+#[derive(Debug, Clone, Default)]
+pub struct FrameTimeline {
+    pub frame: u64,
+    /// The wall time of the frame, in nanoseconds.
+    pub wall: u64,
+    pub spans: Vec<TimelineSpan>,
+    /// The manual spans which ended during the frame.
+    pub async_spans: Vec<AsyncSpan>,
+    /// The markers recorded during the frame.
+    pub marks: Vec<Marker>,
+    /// The log records emitted during the frame.
+    pub logs: Vec<TimelineLog>,
+    /// The number of events left out once `HitchCapture::max_frame_events` was reached.
+    pub dropped: usize,
+}
+
+/// A captured slow frame.
+#[derive(Debug, Clone)]
+pub struct Hitch {
+    pub frame: u64,
+    /// The wall time of the hitch frame, in nanoseconds.
+    pub wall: u64,
+    /// The timelines of the preceding frames, oldest first, followed by the hitch frame itself.
+    pub timeline: Vec<FrameTimeline>,
+}
+
+/// Collects frame timelines from the event stream and keeps the worst ones.
+pub(crate) struct HitchRecorder {
+    settings: HitchCapture,
+    current: FrameTimeline,
+    previous: VecDeque<FrameTimeline>,
+    hitches: Vec<Hitch>,
+}
+impl HitchRecorder {
+    pub(crate) fn new(settings: HitchCapture) -> Self {
+        Self {
+            settings,
+            current: FrameTimeline::default(),
+            previous: VecDeque::new(),
+            hitches: Vec::new(),
+        }
+    }
+
+    pub(crate) fn hitches(&self) -> &[Hitch] {
+        &self.hitches
+    }
+
+    pub(crate) fn take_hitches(&mut self) -> Vec<Hitch> {
+        std::mem::take(&mut self.hitches)
+    }
+
+    pub(crate) fn span(&mut self, span: TimelineSpan) {
+        if self.has_room() {
+            self.current.spans.push(span);
+        }
+    }
+
+    pub(crate) fn async_span(&mut self, span: AsyncSpan) {
+        if self.has_room() {
+            self.current.async_spans.push(span);
+        }
+    }
+
+    pub(crate) fn mark(&mut self, marker: Marker) {
+        if self.has_room() {
+            self.current.marks.push(marker);
+        }
+    }
+
+    pub(crate) fn log(&mut self, log: TimelineLog) {
+        if self.has_room() {
+            self.current.logs.push(log);
+        }
+    }
+
+    /// Returns true if the current frame can take another event, counting it as dropped if not.
+    fn has_room(&mut self) -> bool {
+        let current = &mut self.current;
+        let events = current.spans.len()
+            + current.async_spans.len()
+            + current.marks.len()
+            + current.logs.len();
+        if events < self.settings.max_frame_events {
+            true
+        } else {
+            current.dropped += 1;
+            false
+        }
+    }
+
+    pub(crate) fn end_frame(&mut self, frame: u64, wall: u64) {
+        let mut current = std::mem::take(&mut self.current);
+        current.frame = frame;
+        current.wall = wall;
+
+        if wall > self.settings.threshold.as_nanos() as u64 && self.settings.capacity > 0 {
+            let mut timeline: Vec<FrameTimeline> = self.previous.iter().cloned().collect();
+            timeline.push(current.clone());
+            self.push(Hitch {
+                frame,
+                wall,
+                timeline,
+            });
+        }
+
+        if self.settings.previous_frames > 0 {
+            if self.previous.len() == self.settings.previous_frames {
+                self.previous.pop_front();
+            }
+            self.previous.push_back(current);
+        }
+    }
+
+    fn push(&mut self, hitch: Hitch) {
+        if self.hitches.len() < self.settings.capacity {
+            self.hitches.push(hitch);
+            return;
+        }
+
+        if let Some((index, least)) = self
+            .hitches
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, hitch)| hitch.wall)
+        {
+            if least.wall < hitch.wall {
+                self.hitches[index] = hitch;
+            }
+        }
+    }
+}
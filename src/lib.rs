@@ -5,7 +5,9 @@
 /// context; meaning span metrics and frame metrics; although many better libraries exist, these are
 /// all mainly geared towards web, server and async contexts.
 ///
-/// The `disable` feature exists to disable the system at compile time.
+/// The `disable` feature exists to disable the system at compile time. With it enabled, `scope!`
+/// and `#[instrument]` expand to nothing, `Span` is a zero sized type and `Metrics` keeps its API
+/// but never spawns a worker or collects any data.
 ///
 /// # Example
 /// ```
@@ -54,9 +56,8 @@
 ///t1.join().unwrap();
 ///t2.join().unwrap();
 ///
-///metrics.for_each_histogram(|span_name, h| {
-///    println!("{} -> {:.2}ms", span_name, h.mean() / 1_000_000.0);
-///});
+///metrics.flush();
+///println!("{}", metrics);
 ///
 /// ```
 
@@ -65,7 +66,92 @@
 mod metrics;
 
 #[cfg(feature = "metrics")]
-pub use metrics::{Event, Metrics, Span};
+mod allocation;
+
+#[cfg(feature = "metrics")]
+mod callsite;
+
+#[cfg(feature = "metrics")]
+mod category;
+
+#[cfg(feature = "metrics")]
+mod collector;
+
+#[cfg(feature = "metrics")]
+mod clock;
+
+#[cfg(feature = "metrics")]
+mod flamegraph;
+
+#[cfg(feature = "metrics")]
+mod frame_stats;
+
+#[cfg(feature = "metrics")]
+mod hitch;
+
+#[cfg(feature = "metrics")]
+mod report;
+
+#[cfg(feature = "metrics")]
+mod sampling;
+
+#[cfg(feature = "metrics")]
+pub mod testing;
+
+#[cfg(feature = "profiler-stream")]
+mod profiler_stream;
+
+#[cfg(feature = "tracing")]
+mod tracing_layer;
+
+#[cfg(feature = "facade")]
+mod facade;
+
+#[cfg(feature = "metrics")]
+pub use metrics::{Event, EventSink, Metrics, Span, SpanId};
+
+#[cfg(feature = "metrics")]
+pub use allocation::Allocations;
+
+#[cfg(feature = "alloc")]
+pub use allocation::TrackingAllocator;
+
+#[cfg(feature = "metrics")]
+pub use callsite::{callsite, for_each_callsite, Callsite, CallsiteId};
+
+#[cfg(feature = "metrics")]
+pub use category::{
+    is_category_enabled, is_enabled, set_category_enabled, set_enabled, Category, MAX_CATEGORIES,
+};
+
+#[cfg(feature = "metrics")]
+pub use clock::{
+    reset_clock_source, set_clock_source, ClockSource, MockClock, QuantaClock, StdClock,
+};
+
+#[cfg(feature = "metrics")]
+pub use collector::{with_collector, Collector};
+
+#[cfg(feature = "metrics")]
+pub use frame_stats::FrameStats;
+
+#[cfg(feature = "metrics")]
+pub use hitch::{AsyncSpan, FrameTimeline, Hitch, HitchCapture, Marker, TimelineLog, TimelineSpan};
+
+#[cfg(feature = "metrics")]
+pub use report::{Report, ReportRow, ReportSort, ReportValue};
+
+#[cfg(feature = "metrics")]
+pub use sampling::{clear_sampling, set_sampling, SamplePolicy, Sampler};
+
+#[cfg(feature = "profiler-stream")]
+pub use profiler_stream::{ProfilerBridge, ProfilerMessage};
+
+#[cfg(feature = "tracing")]
+pub use tracing_layer::MetricsLayer;
+
+#[cfg(feature = "facade")]
+pub use facade::FacadeRecorder;
 
 #[cfg(feature = "metrics")]
 pub use game_metrics_macro::instrument;
@@ -74,7 +160,16 @@ pub use game_metrics_macro::instrument;
 mod logging;
 
 #[cfg(feature = "logging")]
-pub use logging::{Logger, Settings as LoggerSettings};
+mod appender;
+
+#[cfg(feature = "logging")]
+pub use logging::{LogRecord, Logger, ParseSettingsError, Settings as LoggerSettings};
+
+#[cfg(all(feature = "logging", not(feature = "threads")))]
+pub use logging::SwapSettingsError;
+
+#[cfg(feature = "logging")]
+pub use appender::{Appender, ConsoleAppender, JsonAppender};
 
 #[cfg(feature = "logging")]
-pub use log::Level as LogLevel;
\ No newline at end of file
+pub use log::{Level as LogLevel, LevelFilter as LogLevelFilter};
\ No newline at end of file
@@ -2,7 +2,7 @@
 #[cfg(feature = "threads")]
 use crossbeam_channel::{Receiver, Sender};
 #[cfg(feature = "threads")]
-use parking_lot::Mutex;
+use parking_lot::{Mutex, RwLock};
 #[cfg(feature = "threads")]
 use std::{
     sync::{
@@ -14,11 +14,18 @@ use std::{
 #[cfg(not(feature = "threads"))]
 use std::{
     cell::RefCell,
-    collections::VecDeque
+    collections::VecDeque,
+    sync::atomic::{AtomicUsize, Ordering},
+    thread::ThreadId,
 };
 
+use crate::appender::{Appender, ConsoleAppender};
 use fxhash::FxHashMap;
-use log::{Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
+use log::{
+    kv::{self, Key, Value, VisitSource},
+    LevelFilter, Log, Metadata, Record, SetLoggerError,
+};
+use std::{fmt, time::SystemTime};
 
 #[cfg(feature = "threads")]
 lazy_static::lazy_static! {
@@ -30,26 +37,201 @@ lazy_static::lazy_static! {
     static ref LOGGER: Logger = Logger::default();
 }
 
-struct InternalRecord {
-    target: String,
-    level: log::Level,
-    message: String,
-    module_path: Option<String>,
-    file: Option<String>,
-    line: Option<u32>,
+/// An owned copy of a `log::Record`, as handed to each `Appender` by the logger.
+pub struct LogRecord {
+    pub timestamp: SystemTime,
+    pub target: String,
+    pub level: log::Level,
+    pub message: String,
+    pub module_path: Option<String>,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+    /// The name of the thread which emitted the record, or its id if it is unnamed.
+    pub thread: String,
+    /// Structured key/value pairs attached with the `log` macros, e.g.
+    /// `info!(entity = id; "spawned")`.
+    pub fields: Vec<(String, String)>,
+    /// The active metrics span path on the emitting thread, e.g. `frame>update>physics`.
+    pub span_path: Option<String>,
+    /// The metrics frame number at the time the record was emitted.
+    pub frame: Option<u64>,
+}
+impl LogRecord {
+    fn new(record: &Record) -> Self {
+        let thread = std::thread::current();
+
+        let mut fields = FieldVisitor(Vec::new());
+        record.key_values().visit(&mut fields).ok();
+
+        Self {
+            timestamp: SystemTime::now(),
+            target: record.target().to_owned(),
+            level: record.level(),
+            message: format!("{}", record.args()),
+            module_path: record.module_path().map(ToOwned::to_owned),
+            file: record.file().map(ToOwned::to_owned),
+            line: record.line(),
+            thread: thread
+                .name()
+                .map_or_else(|| format!("{:?}", thread.id()), ToOwned::to_owned),
+            fields: fields.0,
+            #[cfg(feature = "metrics")]
+            span_path: crate::Span::current_path(),
+            #[cfg(not(feature = "metrics"))]
+            span_path: None,
+            #[cfg(feature = "metrics")]
+            frame: Some(crate::Metrics::current_frame()),
+            #[cfg(not(feature = "metrics"))]
+            frame: None,
+        }
+    }
+}
+
+struct FieldVisitor(Vec<(String, String)>);
+impl<'kvs> VisitSource<'kvs> for FieldVisitor {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        self.0.push((key.to_string(), value.to_string()));
+        Ok(())
+    }
+}
+
+/// Error returned when a log directive string fails to parse.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseSettingsError {
+    directive: String,
+}
+impl fmt::Display for ParseSettingsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid log directive `{}`", self.directive)
+    }
+}
+impl std::error::Error for ParseSettingsError {}
+
+/// Error returned when the settings of the single threaded logger can't be changed.
+#[cfg(not(feature = "threads"))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SwapSettingsError {
+    /// The directive string failed to parse.
+    Parse(ParseSettingsError),
+    /// The settings were changed from a thread other than the one the logger is bound to.
+    NotOwner,
+}
+#[cfg(not(feature = "threads"))]
+impl fmt::Display for SwapSettingsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SwapSettingsError::Parse(e) => e.fmt(f),
+            SwapSettingsError::NotOwner => write!(
+                f,
+                "logger settings may only be changed from the thread the logger is bound to"
+            ),
+        }
+    }
+}
+#[cfg(not(feature = "threads"))]
+impl std::error::Error for SwapSettingsError {}
+#[cfg(not(feature = "threads"))]
+impl From<ParseSettingsError> for SwapSettingsError {
+    fn from(e: ParseSettingsError) -> Self {
+        SwapSettingsError::Parse(e)
+    }
 }
 
 pub struct Settings {
-    pub targets: FxHashMap<String, Level>,
+    /// Per-target level filters, keyed by `::` delimited module path. The most specific path
+    /// matching a record wins, so `game` matches `game::net` but not `gameplay`.
+    ///
+    /// # Example
+    /// ```
+    /// use game_metrics::{LogLevel, Logger, LoggerSettings};
+    /// use log::{Log, Metadata};
+    ///
+    /// let settings = LoggerSettings::from_directives("warn,game=debug,game::net=error").unwrap();
+    /// let logger = Logger::new(settings);
+    /// let enabled = |target, level| {
+    ///     logger.enabled(&Metadata::builder().target(target).level(level).build())
+    /// };
+    ///
+    /// assert!(enabled("game::render", LogLevel::Debug));
+    /// assert!(!enabled("game::net", LogLevel::Warn));
+    /// assert!(enabled("game::net::socket", LogLevel::Error));
+    /// assert!(!enabled("gameplay", LogLevel::Debug));
+    /// assert!(enabled("gameplay", LogLevel::Warn));
+    /// ```
+    pub targets: FxHashMap<String, LevelFilter>,
+    /// The level used for records which match no entry in `targets`.
+    pub default: LevelFilter,
+    /// Outputs which every enabled record is written to.
+    pub appenders: Vec<Box<dyn Appender>>,
+    /// Also send enabled records into the metrics event stream as `Event::Log` instant events,
+    /// so they can be lined up with spans. Requires the `metrics` feature.
+    pub span_events: bool,
     paths: Vec<String>,
+    /// Write records as soon as they are logged, rather than queueing them until `Log::flush`.
     #[cfg(not(feature = "threads"))]
-    autoflush: bool,
+    pub autoflush: bool,
+}
+impl Settings {
+    /// Builds settings from an `env_logger` style directive string, such as
+    /// `"warn,game::net=debug,render=trace"`.
+    ///
+    /// A bare level sets the default level, a bare target enables everything for that target and
+    /// `target=level` sets the level for that target. Regex filters (`/foo`) are not supported.
+    ///
+    /// # Example
+    /// ```
+    /// use game_metrics::{LogLevelFilter, LoggerSettings};
+    ///
+    /// let settings = LoggerSettings::from_directives("warn,game::net=debug,render").unwrap();
+    /// assert_eq!(settings.default, LogLevelFilter::Warn);
+    /// assert_eq!(settings.targets["game::net"], LogLevelFilter::Debug);
+    /// assert_eq!(settings.targets["render"], LogLevelFilter::Trace);
+    /// ```
+    pub fn from_directives(directives: &str) -> Result<Self, ParseSettingsError> {
+        let mut settings = Self::default();
+
+        for directive in directives.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            let error = || ParseSettingsError {
+                directive: directive.to_owned(),
+            };
+
+            let mut parts = directive.splitn(2, '=');
+            let name = parts.next().unwrap_or_default().trim();
+            match parts.next().map(str::trim) {
+                Some(level) => {
+                    if name.is_empty() {
+                        return Err(error());
+                    }
+                    let level = level.parse().map_err(|_| error())?;
+                    settings.targets.insert(name.to_owned(), level);
+                }
+                None => {
+                    if let Ok(level) = name.parse() {
+                        settings.default = level;
+                    } else {
+                        settings.targets.insert(name.to_owned(), LevelFilter::Trace);
+                    }
+                }
+            }
+        }
+
+        Ok(settings)
+    }
+
+    /// Builds settings from the directive string held in the environment variable `var`. If the
+    /// variable is not set, the default settings are returned.
+    pub fn from_env(var: &str) -> Result<Self, ParseSettingsError> {
+        std::env::var(var).map_or_else(|_| Ok(Self::default()), |d| Self::from_directives(&d))
+    }
 }
 #[cfg(feature = "threads")]
 impl Default for Settings {
     fn default() -> Self {
         Self {
             targets: FxHashMap::default(),
+            default: LevelFilter::Off,
+            appenders: vec![Box::new(ConsoleAppender)],
+            span_events: false,
             paths: Vec::default(),
         }
     }
@@ -59,33 +241,98 @@ impl Default for Settings {
     fn default() -> Self {
         Self {
             targets: FxHashMap::default(),
+            default: LevelFilter::Off,
+            appenders: vec![Box::new(ConsoleAppender)],
+            span_events: false,
             paths: Vec::default(),
             autoflush: true,
         }
     }
 }
 
-#[cfg(not(feature = "threads"))]
-unsafe impl Send for Settings {}
+#[derive(Default)]
+struct FilterNode {
+    level: Option<LevelFilter>,
+    children: FxHashMap<String, FilterNode>,
+}
 
-#[cfg(not(feature = "threads"))]
-unsafe impl Sync for Settings {}
+/// A trie of module path segments compiled from `Settings::targets`. This is rebuilt only when
+/// the settings are swapped, so `Log::enabled` never touches the settings themselves.
+struct Filter {
+    root: FilterNode,
+    max_level: LevelFilter,
+    span_events: bool,
+}
+impl Filter {
+    fn new(settings: &Settings) -> Self {
+        let mut root = FilterNode {
+            level: Some(settings.default),
+            children: FxHashMap::default(),
+        };
+        let mut max_level = settings.default;
+
+        for (target, level) in &settings.targets {
+            let node = target.split("::").fold(&mut root, |node, segment| {
+                node.children.entry(segment.to_owned()).or_default()
+            });
+            node.level = Some(*level);
+            max_level = max_level.max(*level);
+        }
+
+        Self {
+            root,
+            max_level,
+            span_events: settings.span_events,
+        }
+    }
+
+    fn level_for(&self, target: &str) -> LevelFilter {
+        let mut node = &self.root;
+        let mut level = self.root.level.unwrap_or(LevelFilter::Off);
+
+        for segment in target.split("::") {
+            match node.children.get(segment) {
+                Some(child) => {
+                    node = child;
+                    level = child.level.unwrap_or(level);
+                }
+                None => break,
+            }
+        }
+
+        level
+    }
+
+    fn enabled(&self, meta: &Metadata) -> bool {
+        meta.level() <= self.max_level && meta.level() <= self.level_for(meta.target())
+    }
+}
+
+#[cfg(feature = "metrics")]
+fn forward_to_metrics(filter: &Filter, record: &LogRecord) {
+    if filter.span_events {
+        crate::metrics::send_log(record.level.as_str(), &record.target, &record.message);
+    }
+}
+#[cfg(not(feature = "metrics"))]
+fn forward_to_metrics(filter: &Filter, record: &LogRecord) {}
 
 #[cfg(feature = "threads")]
 pub struct Logger {
     worker_handle: Option<JoinHandle<()>>,
     worker_flag: Arc<AtomicBool>,
     settings: Arc<Mutex<Settings>>,
-    channel: (Sender<InternalRecord>, Receiver<InternalRecord>),
+    filter: RwLock<Filter>,
+    channel: (Sender<LogRecord>, Receiver<LogRecord>),
 }
 #[cfg(feature = "threads")]
 impl Logger {
     pub fn new(settings: Settings) -> Self {
         let worker_flag = Arc::new(AtomicBool::new(true));
 
-        let channel: (Sender<InternalRecord>, Receiver<InternalRecord>) =
-            crossbeam_channel::bounded(4096);
+        let channel: (Sender<LogRecord>, Receiver<LogRecord>) = crossbeam_channel::bounded(4096);
 
+        let filter = RwLock::new(Filter::new(&settings));
         let settings = Arc::new(Mutex::new(settings));
 
         let inner_flag = worker_flag.clone();
@@ -94,8 +341,12 @@ impl Logger {
 
         let worker_handle = std::thread::spawn(move || {
             while inner_flag.load(Ordering::Relaxed) {
-                while let Ok(event) = receiver.recv() {
-                    println!("{}", event.message.to_string());
+                while let Ok(record) = receiver.recv() {
+                    worker_settings
+                        .lock()
+                        .appenders
+                        .iter_mut()
+                        .for_each(|appender| appender.append(&record));
                 }
             }
         });
@@ -104,118 +355,241 @@ impl Logger {
             worker_handle: Some(worker_handle),
             worker_flag,
             settings,
+            filter,
             channel,
         }
     }
 
-    #[cfg(feature = "threads")]
-    pub fn swap_settings(settings: Settings) {
-        *(*LOGGER).settings.lock() = settings;
+    /// Replaces the settings of the global logger, returning the previous settings.
+    pub fn swap_settings(settings: Settings) -> Settings {
+        let filter = Filter::new(&settings);
+        log::set_max_level(filter.max_level);
+        *LOGGER.filter.write() = filter;
+
+        std::mem::replace(&mut *LOGGER.settings.lock(), settings)
     }
 
-    #[cfg(not(feature = "threads"))]
-    pub fn swap_settings(settings: Settings) {
-        LOGGER.settings = settings;
+    /// Parses `directives` and applies them to the global logger. This is intended to be wired
+    /// to a dev console command, so invalid input leaves the current settings untouched.
+    pub fn swap_directives(directives: &str) -> Result<(), ParseSettingsError> {
+        Self::swap_settings(Settings::from_directives(directives)?);
+        Ok(())
     }
 
     pub fn init() -> Result<(), SetLoggerError> {
-        log::set_logger(&*LOGGER.as_ref()).map(|()| log::set_max_level(LevelFilter::Trace))
+        log::set_logger(LOGGER.as_ref())
+            .map(|()| log::set_max_level(LOGGER.filter.read().max_level))
     }
 }
 #[cfg(feature = "threads")]
 impl Log for Logger {
     fn enabled(&self, meta: &Metadata) -> bool {
-        let settings = self.settings.lock();
-
-        settings
-            .targets
-            .get(meta.target())
-            .map(|level| *level <= meta.level())
-            .unwrap_or(false) || settings.targets.iter().any(|(key, level)| {
-            *level <= meta.level() && meta.target().starts_with(key)
-        })
+        self.filter.read().enabled(meta)
     }
     fn log(&self, record: &Record) {
-        if self.enabled(record.metadata()) {
-            self.channel.0.send(InternalRecord {
-                target: record.target().to_owned(),
-                level: record.level(),
-                message: format!("{}", record.args()),
-                module_path: record.module_path().map(|s| s.to_owned()),
-                file: record.file().map(|s| s.to_owned()),
-                line: record.line(),
-            }).unwrap_or_else(|e| println!("Failed to write channel: {:?}", e) );
+        let filter = self.filter.read();
+        if filter.enabled(record.metadata()) {
+            let record = LogRecord::new(record);
+            forward_to_metrics(&filter, &record);
+
+            self.channel
+                .0
+                .send(record)
+                .unwrap_or_else(|e| println!("Failed to write channel: {:?}", e));
         }
     }
 
-    fn flush(&self) {}
+    fn flush(&self) {
+        self.settings
+            .lock()
+            .appenders
+            .iter_mut()
+            .for_each(|appender| appender.flush());
+    }
 }
 
 #[cfg(feature = "threads")]
 impl Drop for Logger {
     fn drop(&mut self) {
         self.worker_flag.store(false, Ordering::Relaxed);
+        // Dropping the sender wakes the worker from `recv` once the queue is drained.
+        self.channel = crossbeam_channel::bounded(0);
         self.worker_handle.take().unwrap().join().unwrap();
     }
 }
 
+/// The single threaded logger, used when the `threads` feature is disabled.
+///
+/// The logger is bound to the thread which created it. The global logger is created lazily, so
+/// it is bound to the thread which first calls `Logger::init`, `Logger::swap_settings`,
+/// `Logger::swap_directives` or `Logger::set_autoflush`. Records logged from any other thread
+/// are discarded, and changing settings from another thread returns
+/// `SwapSettingsError::NotOwner`.
+///
+/// # Example
+/// ```
+/// use game_metrics::{
+///     Appender, LogLevel, LogLevelFilter, LogRecord, Logger, LoggerSettings, SwapSettingsError,
+/// };
+/// use log::{Log, Metadata, Record};
+/// use std::sync::{Arc, Mutex};
+///
+/// struct Capture(Arc<Mutex<Vec<String>>>);
+/// impl Appender for Capture {
+///     fn append(&mut self, record: &LogRecord) {
+///         self.0.lock().unwrap().push(record.message.clone());
+///     }
+/// }
+///
+/// let written = Arc::new(Mutex::new(Vec::new()));
+/// let mut settings = LoggerSettings::from_directives("warn").unwrap();
+/// settings.appenders = vec![Box::new(Capture(written.clone()))];
+/// settings.autoflush = false;
+/// let logger = Logger::new(settings);
+///
+/// let log = |level, message| {
+///     logger.log(&Record::builder().level(level).args(format_args!("{}", message)).build())
+/// };
+/// log(LogLevel::Info, "filtered");
+/// log(LogLevel::Warn, "queued");
+/// assert!(written.lock().unwrap().is_empty());
+/// logger.flush();
+/// assert_eq!(*written.lock().unwrap(), vec!["queued".to_owned()]);
+///
+/// // The global logger is bound to the thread which first uses it.
+/// Logger::init().unwrap();
+/// Logger::swap_directives("info").unwrap();
+/// std::thread::spawn(|| {
+///     let metadata = Metadata::builder().level(LogLevel::Error).build();
+///     assert!(!log::logger().enabled(&metadata));
+///     assert!(Logger::init().is_err());
+///     assert_eq!(Logger::set_autoflush(false), Err(SwapSettingsError::NotOwner));
+///     assert_eq!(Logger::swap_directives("trace"), Err(SwapSettingsError::NotOwner));
+/// })
+/// .join()
+/// .unwrap();
+/// assert_eq!(log::max_level(), LogLevelFilter::Info);
+/// ```
 #[cfg(not(feature = "threads"))]
 pub struct Logger {
-    queue: RefCell<VecDeque<InternalRecord>>,
-    settings: Settings,
+    owner: ThreadId,
+    queue: RefCell<VecDeque<LogRecord>>,
+    settings: RefCell<Settings>,
+    filter: RefCell<Filter>,
+    /// The most verbose level of `filter`, as a `LevelFilter` discriminant, readable from any
+    /// thread.
+    max_level: AtomicUsize,
 }
 #[cfg(not(feature = "threads"))]
 impl Logger {
     pub fn new(settings: Settings) -> Self {
         Self {
-            settings,
+            owner: std::thread::current().id(),
+            max_level: AtomicUsize::new(Filter::new(&settings).max_level as usize),
+            filter: RefCell::new(Filter::new(&settings)),
+            settings: RefCell::new(settings),
             queue: RefCell::new(VecDeque::with_capacity(1024)),
         }
     }
 
+    fn is_owner(&self) -> bool {
+        std::thread::current().id() == self.owner
+    }
+
+    /// Replaces the settings of the global logger, returning the previous settings.
+    pub fn swap_settings(settings: Settings) -> Result<Settings, SwapSettingsError> {
+        if !LOGGER.is_owner() {
+            return Err(SwapSettingsError::NotOwner);
+        }
+
+        let filter = Filter::new(&settings);
+        log::set_max_level(filter.max_level);
+        LOGGER
+            .max_level
+            .store(filter.max_level as usize, Ordering::Relaxed);
+        LOGGER.filter.replace(filter);
+
+        Ok(LOGGER.settings.replace(settings))
+    }
+
+    /// Parses `directives` and applies them to the global logger. This is intended to be wired
+    /// to a dev console command, so invalid input leaves the current settings untouched.
+    pub fn swap_directives(directives: &str) -> Result<(), SwapSettingsError> {
+        Self::swap_settings(Settings::from_directives(directives)?)?;
+        Ok(())
+    }
+
+    /// Enables or disables autoflush on the global logger. When disabled, records are queued
+    /// until `Log::flush` is called.
+    pub fn set_autoflush(autoflush: bool) -> Result<(), SwapSettingsError> {
+        if !LOGGER.is_owner() {
+            return Err(SwapSettingsError::NotOwner);
+        }
+
+        LOGGER.settings.borrow_mut().autoflush = autoflush;
+        Ok(())
+    }
+
+    /// Installs the global logger, binding it to the calling thread if it is not bound yet.
     pub fn init() -> Result<(), SetLoggerError> {
-        log::set_logger(&*LOGGER).map(|()| log::set_max_level(LevelFilter::Trace))
+        log::set_logger(&*LOGGER).map(|()| {
+            let max_level = LOGGER.max_level.load(Ordering::Relaxed);
+            let max_level = LevelFilter::iter().nth(max_level);
+            log::set_max_level(max_level.unwrap_or(LevelFilter::Off));
+        })
+    }
+
+    fn append(&self, record: &LogRecord) {
+        if let Ok(mut settings) = self.settings.try_borrow_mut() {
+            settings
+                .appenders
+                .iter_mut()
+                .for_each(|appender| appender.append(record));
+        }
     }
 }
 #[cfg(not(feature = "threads"))]
 impl Log for Logger {
     fn enabled(&self, meta: &Metadata) -> bool {
-        self.settings
-            .targets
-            .get(meta.target())
-            .map(|level| *level <= meta.level())
-            .unwrap_or(false) || self.settings.targets.iter().any(|(key, level)| {
-                *level <= meta.level() && meta.target().starts_with(key)
-            })
+        self.is_owner() && self.filter.borrow().enabled(meta)
     }
 
     fn log(&self, record: &Record) {
-        let event = InternalRecord {
-            target: record.target().to_owned(),
-            level: record.level(),
-            message: format!("{}", record.args()),
-            module_path: record.module_path().map(|s| s.to_owned()),
-            file: record.file().map(|s| s.to_owned()),
-            line: record.line(),
-        };
-        if self.enabled(record.metadata()) && !self.settings.autoflush {
-            self.queue.borrow_mut().push_back(event);
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let event = LogRecord::new(record);
+        forward_to_metrics(&self.filter.borrow(), &event);
+
+        if self.settings.borrow().autoflush {
+            self.append(&event);
         } else {
-            println!("{}", event.message.to_string());
+            self.queue.borrow_mut().push_back(event);
         }
     }
 
     fn flush(&self) {
-        let mut queue = self.queue.borrow_mut();
-        while let Some(event) = queue.pop_front() {
-            println!("{}", event.message.to_string());
+        if !self.is_owner() {
+            return;
+        }
+
+        loop {
+            let event = self.queue.borrow_mut().pop_front();
+            match event {
+                Some(event) => self.append(&event),
+                None => break,
+            }
+        }
+        if let Ok(mut settings) = self.settings.try_borrow_mut() {
+            settings.appenders.iter_mut().for_each(|appender| appender.flush());
         }
     }
 }
 
-#[cfg(not(feature = "threads"))]
-unsafe impl Send for Logger {}
-
+// SAFETY: every access to the `RefCell`s is guarded by `is_owner`, so they are only ever touched
+// from the single thread the logger is bound to. `Logger::init`, which may run on any thread,
+// only reads the atomic `max_level`.
 #[cfg(not(feature = "threads"))]
 unsafe impl Sync for Logger {}
 
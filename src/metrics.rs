@@ -16,20 +16,23 @@
 //!    let do_stuff = 1 + 1;
 //! }
 //!
-//! let metrics = Metrics::new(1);
+//! fn main() {
+//!     let metrics = Metrics::new(1);
 //!
-//! (0..1000).for_each(|_| scoped());
-//! (0..1000).for_each(|_| named());
+//!     (0..1000).for_each(|_| scoped());
+//!     (0..1000).for_each(|_| named());
 //!
-//! let mut count = 0;
+//!     let mut count = 0;
 //!
-//! metrics.flush();
-//! metrics.for_each_histogram(|span_name, h| {
-//!     println!("{}", span_name);
-//!     assert!(h.mean() > 0.0);
-//!     count += 1;
-//! });
-//! assert_eq!(count, 2);
+//!     metrics.flush();
+//!     metrics.for_each_histogram(|span_name, h| {
+//!         println!("{}", span_name);
+//!         assert!(h.mean() > 0.0);
+//!         count += 1;
+//!     });
+//!     assert_eq!(count, 2);
+//!     assert_eq!(metrics.estimated_calls(concat!(module_path!(), "::scoped")), Some(1000));
+//! }
 //! ```
 //!
 //! ```
@@ -59,29 +62,76 @@ use std::{
     collections::VecDeque
 };
 
+use crate::{
+    allocation::Allocations,
+    callsite::{Callsite, CallsiteId},
+    category::Category,
+    flamegraph::StackTree,
+    frame_stats::FrameStats,
+    hitch::{AsyncSpan, Hitch, HitchCapture, HitchRecorder, Marker, TimelineLog, TimelineSpan},
+    report::{Report, ReportRow, ReportValue},
+    sampling::Sampler,
+};
 use fxhash::FxHashMap;
 pub use hdrhistogram as histogram;
 use hdrhistogram::Histogram;
 use parking_lot::Mutex;
-use quanta::Clock;
+#[cfg(feature = "threads")]
+use std::thread::JoinHandle;
 use std::{
+    collections::BTreeMap,
     sync::{
-        atomic::{AtomicBool, AtomicU32, Ordering},
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
         Arc,
     },
-    thread::JoinHandle,
+    time::Duration,
 };
 
 lazy_static::lazy_static! {
     static ref CHANNEL: Channel = Channel::new();
 }
 
+static FRAME: AtomicU64 = AtomicU64::new(0);
+static FRAME_START: AtomicU64 = AtomicU64::new(u64::MAX);
+static SUSPENDED: AtomicBool = AtomicBool::new(false);
+
+static NEXT_THREAD: AtomicU64 = AtomicU64::new(0);
+static NEXT_SPAN_ID: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    static SPAN_STACK: std::cell::RefCell<Vec<(&'static str, CallsiteId)>> =
+        const { std::cell::RefCell::new(Vec::new()) };
+    static THREAD_INDEX: u64 = NEXT_THREAD.fetch_add(1, Ordering::Relaxed);
+}
+
 #[cfg(not(feature = "disable"))]
 #[macro_export]
 macro_rules! scope(
     ($span_name:literal) => (
-        let __INSTR_METRICS_SCOPE = $crate::Span::new($span_name);
-    )
+        let __INSTR_METRICS_SCOPE = {
+            static __INSTR_METRICS_CALLSITE: $crate::Callsite =
+                $crate::Callsite::new($span_name, module_path!(), file!(), line!());
+            $crate::Span::with_callsite(&__INSTR_METRICS_CALLSITE, None, None)
+        };
+    );
+    ($span_name:literal $(, category = $category:literal)? $(, sample = $sample:literal)?) => (
+        let __INSTR_METRICS_SCOPE = {
+            static __INSTR_METRICS_CALLSITE: $crate::Callsite =
+                $crate::Callsite::new($span_name, module_path!(), file!(), line!());
+            let category: Option<&'static $crate::Category> = None;
+            $(
+                static __INSTR_METRICS_CATEGORY: $crate::Category = $crate::Category::new($category);
+                let category = Some(&__INSTR_METRICS_CATEGORY);
+            )?
+            let sampler: Option<&'static $crate::Sampler> = None;
+            $(
+                static __INSTR_METRICS_SAMPLER: $crate::Sampler =
+                    $crate::Sampler::new($crate::SamplePolicy::OneIn($sample));
+                let sampler = Some(&__INSTR_METRICS_SAMPLER);
+            )?
+            $crate::Span::with_callsite(&__INSTR_METRICS_CALLSITE, category, sampler)
+        };
+    );
 );
 
 #[cfg(feature = "disable")]
@@ -89,17 +139,126 @@ macro_rules! scope(
 macro_rules! scope(
     ($span_name:literal) => (
 
-    )
+    );
+    ($span_name:literal $(, category = $category:literal)? $(, sample = $sample:literal)?) => (
+
+    );
+);
+
+/// Records an instant marker, e.g. `mark!("level_loaded")` or `mark!("gc_triggered", count = n)`.
+/// Field values are formatted with `Display`, and only when recording is enabled. With the
+/// `disable` feature they are not evaluated at all.
+#[cfg(not(feature = "disable"))]
+#[macro_export]
+macro_rules! mark(
+    ($name:literal $(, $key:ident = $value:expr)* $(,)?) => (
+        $crate::Metrics::mark_with($name, || vec![$((stringify!($key), $value.to_string())),*]);
+    );
+);
+
+#[cfg(feature = "disable")]
+#[macro_export]
+macro_rules! mark(
+    ($name:literal $(, $key:ident = $value:expr)* $(,)?) => (
+        { let _ = || { $(let _ = &$value;)* }; }
+    );
 );
 
 /// Events dispatched by various instrumentation functions.
+#[derive(Debug, Clone)]
 pub enum Event {
     /// A `Span` has been entered
-    SpanEnter(&'static str),
+    SpanEnter {
+        callsite: CallsiteId,
+        /// The index of the thread the span runs on, as assigned by this crate.
+        thread: u64,
+        /// The clock time the span was entered, in nanoseconds.
+        time: u64,
+    },
     /// A `Span` has been dropped
     SpanExit {
-        span_name: &'static str,
+        callsite: CallsiteId,
+        /// The clock time the span was entered, in nanoseconds.
+        start: u64,
         elapsed: u64,
+        /// The number of calls this exit stands in for. This is 1 unless the span is sampled.
+        weight: u64,
+        /// The index of the thread the span ran on, as assigned by this crate.
+        thread: u64,
+        /// The nesting depth of the span on its thread, 0 for outermost spans.
+        depth: usize,
+        /// The call site of the span enclosing this one on its thread.
+        parent: Option<CallsiteId>,
+        /// The allocations made within the span, excluding nested spans. Only tracked with the
+        /// `alloc` feature and `TrackingAllocator` installed.
+        allocations: Allocations,
+    },
+    /// A manual span has been started with `Metrics::start_span`.
+    AsyncStart {
+        id: SpanId,
+        callsite: CallsiteId,
+        /// The call site of the innermost `Span` active on the starting thread.
+        parent: Option<CallsiteId>,
+        thread: u64,
+        /// The clock time the span was started, in nanoseconds.
+        start: u64,
+    },
+    /// A manual span has been ended with `Metrics::end_span`.
+    AsyncEnd {
+        id: SpanId,
+        thread: u64,
+        /// The clock time the span was ended, in nanoseconds.
+        end: u64,
+    },
+    /// An instant marker has been recorded with `mark!`.
+    Mark {
+        name: &'static str,
+        thread: u64,
+        /// The clock time the marker was recorded, in nanoseconds.
+        time: u64,
+        fields: Vec<(&'static str, String)>,
+    },
+    /// A frame has ended, sent by `Metrics::next_frame` and `Metrics::next_frame_with_delta`.
+    FrameEnd {
+        frame: u64,
+        /// The clock time the frame ended, in nanoseconds.
+        time: u64,
+        /// The wall clock time the frame took, in nanoseconds.
+        wall: u64,
+        /// The simulation time which passed during the frame, in nanoseconds.
+        sim: u64,
+    },
+    /// A value has been plotted with `Metrics::plot`.
+    Plot {
+        name: &'static str,
+        value: f64,
+        /// The clock time the value was plotted, in nanoseconds.
+        time: u64,
+    },
+    /// A counter has been incremented with `Metrics::count`.
+    Count {
+        name: &'static str,
+        value: u64,
+        /// The clock time the counter was incremented, in nanoseconds.
+        time: u64,
+    },
+    /// A value has been recorded with `Metrics::record`.
+    Record {
+        name: &'static str,
+        value: u64,
+        /// The clock time the value was recorded, in nanoseconds.
+        time: u64,
+    },
+    /// A log record was emitted, sent when `LoggerSettings::span_events` is enabled.
+    Log {
+        level: &'static str,
+        target: String,
+        message: String,
+        span_path: Option<String>,
+        frame: u64,
+        /// The index of the thread the record was logged on.
+        thread: u64,
+        time: u64,
     },
 }
 
@@ -107,7 +266,8 @@ pub enum Event {
 struct Channel {
     subscribers: AtomicU32,
     channel: (Sender<Event>, Receiver<Event>),
-    clock: Clock,
+    /// Events sent but not yet processed by a worker.
+    pending: AtomicU64,
 }
 #[cfg(feature = "threads")]
 impl Channel {
@@ -115,12 +275,13 @@ impl Channel {
         Self {
             subscribers: AtomicU32::new(0),
             channel: crossbeam_channel::unbounded(),
-            clock: Clock::default(),
+            pending: AtomicU64::new(0),
         }
     }
     #[cfg(not(feature = "disable"))]
     fn send(&self, event: Event) {
         if self.subscribers.load(Ordering::SeqCst) > 0 {
+            self.pending.fetch_add(1, Ordering::SeqCst);
             self.channel.0.send(event).unwrap();
         }
     }
@@ -143,28 +304,89 @@ impl Channel {
     }
 }
 
+/// Without `threads`, events are queued in a thread-local owned by the thread which created the
+/// `Metrics` instance. Events sent from any other thread are discarded and counted, so spans on
+/// helper threads (asset loaders and such) are harmless rather than racing on the queue.
 #[cfg(not(feature = "threads"))]
 struct Channel {
-    queue: RefCell<VecDeque<Event>>,
-    clock: Clock,
+    dropped: AtomicU64,
+}
+/// The event queue of the thread which created a `Metrics` instance.
+#[cfg(not(feature = "threads"))]
+struct Queue {
+    /// Shared with the `Metrics` instance, which clears it when dropped on any thread.
+    subscribed: Arc<AtomicBool>,
+    events: VecDeque<Event>,
+}
+#[cfg(not(feature = "threads"))]
+thread_local! {
+    static QUEUE: RefCell<Option<Queue>> = const { RefCell::new(None) };
 }
 #[cfg(not(feature = "threads"))]
 impl Channel {
     fn new() -> Self {
         Self {
-            queue: RefCell::new(VecDeque::with_capacity(1024)),
-            clock: Clock::default(),
+            dropped: AtomicU64::new(0),
         }
     }
 
+    /// Creates the queue of the calling thread, returning the handle to unsubscribe it with.
+    fn subscribe(&self) -> Arc<AtomicBool> {
+        let subscribed = Arc::new(AtomicBool::new(true));
+        QUEUE.with(|queue| {
+            *queue.borrow_mut() = Some(Queue {
+                subscribed: subscribed.clone(),
+                events: VecDeque::with_capacity(1024),
+            })
+        });
+        subscribed
+    }
+
+    /// Unsubscribes the queue `subscribed` was returned for. The queue itself is freed right
+    /// away on its own thread, and on the next send or receive otherwise.
+    fn unsubscribe(&self, subscribed: &Arc<AtomicBool>) {
+        subscribed.store(false, Ordering::Relaxed);
+        QUEUE
+            .try_with(|queue| {
+                let mut queue = queue.borrow_mut();
+                if matches!(&*queue, Some(q) if Arc::ptr_eq(&q.subscribed, subscribed)) {
+                    queue.take();
+                }
+            })
+            .ok();
+    }
+
+    /// Calls `f` with the queue of the calling thread, if it is still subscribed.
+    fn with_queue<F, R>(f: F) -> Option<R>
+    where
+        F: FnOnce(&mut VecDeque<Event>) -> R,
+    {
+        QUEUE
+            .try_with(|queue| {
+                let mut queue = queue.borrow_mut();
+                match queue.as_mut() {
+                    Some(q) if q.subscribed.load(Ordering::Relaxed) => Some(f(&mut q.events)),
+                    Some(_) => {
+                        queue.take();
+                        None
+                    }
+                    None => None,
+                }
+            })
+            .ok()
+            .flatten()
+    }
+
     #[cfg(not(feature = "disable"))]
     fn send(&self, event: Event) {
-        self.queue.borrow_mut().push_back(event);
+        if Self::with_queue(|queue| queue.push_back(event)).is_none() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
     }
 
     #[cfg(not(feature = "disable"))]
     fn recv(&self) -> Option<Event> {
-        self.queue.borrow_mut().pop_front()
+        Self::with_queue(VecDeque::pop_front).flatten()
     }
 
     #[cfg(feature = "disable")]
@@ -175,11 +397,117 @@ impl Channel {
     }
 }
 
-#[cfg(not(feature = "threads"))]
-unsafe impl Send for Channel {}
+/// Receives every event processed by `Metrics`, e.g. to forward it to an external profiler.
+/// Sinks are added with `Metrics::add_sink`. With the `threads` feature each sink runs on a
+/// thread of its own, fed by a bounded queue, so a slow sink never holds up `Metrics`; without
+/// it, sinks are called on the owning thread.
+pub trait EventSink: Send {
+    /// Called with each event, in the order `Metrics` processed them.
+    fn event(&mut self, event: &Event);
 
-#[cfg(not(feature = "threads"))]
-unsafe impl Sync for Channel {}
+    /// Called by `Metrics::flush` once all pending events have been processed.
+    fn flush(&mut self) {}
+}
+
+/// The number of events which may wait for a sink's thread before further events are dropped.
+#[cfg(feature = "threads")]
+const SINK_QUEUE_LEN: usize = 65_536;
+
+/// An `EventSink` running on its own thread. Events are dropped while its queue is full.
+#[cfg(feature = "threads")]
+struct SinkThread {
+    /// Sends events, or `None` to flush the sink.
+    sender: Option<Sender<Option<Event>>>,
+    /// Set when the queue was too full to take a flush request.
+    flush_requested: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+#[cfg(feature = "threads")]
+impl SinkThread {
+    fn spawn(mut sink: Box<dyn EventSink>) -> Self {
+        let (sender, receiver) = crossbeam_channel::bounded(SINK_QUEUE_LEN);
+        let flush_requested = Arc::new(AtomicBool::new(false));
+
+        let flush = flush_requested.clone();
+        let handle = std::thread::spawn(move || {
+            for event in receiver.iter() {
+                match event {
+                    Some(event) => sink.event(&event),
+                    None => sink.flush(),
+                }
+                if receiver.is_empty() && flush.swap(false, Ordering::AcqRel) {
+                    sink.flush();
+                }
+            }
+            sink.flush();
+        });
+
+        Self {
+            sender: Some(sender),
+            flush_requested,
+            handle: Some(handle),
+        }
+    }
+
+    fn event(&mut self, event: &Event) {
+        if let Some(sender) = &self.sender {
+            let _ = sender.try_send(Some(event.clone()));
+        }
+    }
+
+    /// Asks the thread to flush the sink once it has written the events queued so far. If the
+    /// queue is full, the flush happens once the thread has emptied it.
+    fn flush(&mut self) {
+        if let Some(sender) = &self.sender {
+            if sender.try_send(None).is_err() {
+                self.flush_requested.store(true, Ordering::Release);
+            }
+        }
+    }
+}
+#[cfg(feature = "threads")]
+impl Drop for SinkThread {
+    fn drop(&mut self) {
+        self.sender.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Returns the index of the calling thread, as assigned by this crate, or `u64::MAX` once the
+/// thread's locals have been destroyed.
+pub(crate) fn thread_index() -> u64 {
+    THREAD_INDEX.try_with(|index| *index).unwrap_or(u64::MAX)
+}
+
+/// Sends an event to the `Collector` active on this thread, if any, or to the global channel.
+#[inline]
+fn dispatch(event: Event) {
+    crate::allocation::untracked(|| {
+        if let Some(event) = crate::collector::dispatch(event) {
+            CHANNEL.send(event);
+        }
+    })
+}
+
+/// Forwards a log record into the metrics event stream.
+#[cfg(all(feature = "logging", feature = "disable"))]
+pub(crate) fn send_log(_level: &'static str, _target: &str, _message: &str) {}
+
+/// Forwards a log record into the metrics event stream.
+#[cfg(all(feature = "logging", not(feature = "disable")))]
+pub(crate) fn send_log(level: &'static str, target: &str, message: &str) {
+    dispatch(Event::Log {
+        level,
+        target: target.to_owned(),
+        message: message.to_owned(),
+        span_path: Span::current_path(),
+        frame: Metrics::current_frame(),
+        thread: thread_index(),
+        time: crate::clock::now(),
+    });
+}
 
 /// The raw span RAII guard which is used for scoping spans of code for instrumentation.
 ///
@@ -187,153 +515,1322 @@ unsafe impl Sync for Channel {}
 ///
 /// On `Drop`, the `Span` emits an `Event::SpanExit` event, which includes the elapsed time
 /// calculated from the saved start time to the time of drop.
+///
+/// If span collection is disabled at runtime, either globally or for the span's `Category`, the
+/// `Span` is inert and neither reads the clock nor emits any events.
+///
+/// With the `disable` feature, `Span` is a zero sized type which does nothing.
+#[cfg(not(feature = "disable"))]
 pub struct Span {
     name: &'static str,
+    callsite: Option<CallsiteId>,
     start: u64,
+    weight: u64,
+    alloc: Option<crate::allocation::AllocScope>,
 }
+#[cfg(not(feature = "disable"))]
 impl Span {
     pub fn new(name: &'static str) -> Self {
-        CHANNEL.send(Event::SpanEnter(name));
+        Self::with_options(name, None, None)
+    }
+
+    /// Creates a span belonging to `category`, which is only collected while that category is
+    /// enabled.
+    pub fn with_category(name: &'static str, category: &'static Category) -> Self {
+        Self::with_options(name, Some(category), None)
+    }
+
+    /// Creates a span with an optional `Category` and call site `Sampler`. Spans without a
+    /// sampler are sampled according to any policy registered for their name with
+    /// `set_sampling`.
+    ///
+    /// Spans created by name share one `Callsite` per name, which is looked up in a per-thread
+    /// cache. `scope!` and `#[instrument]` use `Span::with_callsite` instead.
+    pub fn with_options(
+        name: &'static str,
+        category: Option<&'static Category>,
+        sampler: Option<&'static Sampler>,
+    ) -> Self {
+        Self::start(name, category, sampler, || crate::callsite::by_name(name))
+    }
+
+    /// Creates a span for a static `Callsite`, like `Span::with_options`.
+    pub fn with_callsite(
+        callsite: &'static Callsite,
+        category: Option<&'static Category>,
+        sampler: Option<&'static Sampler>,
+    ) -> Self {
+        Self::start(callsite.name(), category, sampler, || callsite)
+    }
+
+    #[inline]
+    fn start(
+        name: &'static str,
+        category: Option<&'static Category>,
+        sampler: Option<&'static Sampler>,
+        callsite: impl FnOnce() -> &'static Callsite,
+    ) -> Self {
+        let enabled = !SUSPENDED.load(Ordering::Relaxed)
+            && match category {
+                Some(category) => category.is_enabled(),
+                None => crate::category::is_enabled(),
+            };
+        if !enabled {
+            return Self::inactive(name);
+        }
+
+        let callsite = crate::allocation::untracked(callsite);
+        let weight = match sampler.or_else(|| callsite.registered_sampler()) {
+            Some(sampler) => sampler.sample(),
+            None => Some(1),
+        };
+        match weight {
+            Some(weight) => {
+                Self::enter(name, crate::allocation::untracked(|| callsite.id()), weight)
+            }
+            None => Self::inactive(name),
+        }
+    }
+
+    fn enter(name: &'static str, callsite: CallsiteId, weight: u64) -> Self {
+        let start = crate::clock::now();
+        dispatch(Event::SpanEnter {
+            callsite,
+            thread: THREAD_INDEX.with(|index| *index),
+            time: start,
+        });
+        crate::allocation::untracked(|| {
+            SPAN_STACK.with(|stack| stack.borrow_mut().push((name, callsite)))
+        });
+
+        Self {
+            name,
+            callsite: Some(callsite),
+            alloc: Some(crate::allocation::enter()),
+            start,
+            weight,
+        }
+    }
 
+    fn inactive(name: &'static str) -> Self {
         Self {
             name,
-            start: CHANNEL.clock.now(),
+            callsite: None,
+            start: 0,
+            weight: 0,
+            alloc: None,
         }
     }
+
+    /// Returns the names of the spans currently active on this thread, outermost first and
+    /// joined with `>`, e.g. `frame>update>physics`. Returns `None` outside of any span.
+    pub fn current_path() -> Option<String> {
+        SPAN_STACK.with(|stack| {
+            let stack = stack.borrow();
+            if stack.is_empty() {
+                None
+            } else {
+                let names: Vec<&str> = stack.iter().map(|(name, _)| *name).collect();
+                Some(names.join(">"))
+            }
+        })
+    }
 }
+#[cfg(not(feature = "disable"))]
 impl Drop for Span {
     fn drop(&mut self) {
-        let elapsed = CHANNEL.clock.now() - self.start;
-        CHANNEL.send(Event::SpanExit {
-            span_name: self.name,
+        let callsite = match self.callsite {
+            Some(callsite) => callsite,
+            None => return,
+        };
+
+        let elapsed = crate::clock::now().saturating_sub(self.start);
+        let allocations = self
+            .alloc
+            .as_ref()
+            .map(crate::allocation::exit)
+            .unwrap_or_default();
+        let (depth, parent) = SPAN_STACK.with(|stack| {
+            let mut stack = stack.borrow_mut();
+            stack.pop();
+            (stack.len(), stack.last().map(|(_, callsite)| *callsite))
+        });
+        dispatch(Event::SpanExit {
+            callsite,
+            start: self.start,
             elapsed,
+            weight: self.weight,
+            thread: THREAD_INDEX.with(|index| *index),
+            depth,
+            parent,
+            allocations,
         });
     }
 }
 
-/// The metrics struct is used to initialize the metrics communications channels and access the
-/// `Histogram` data for each named metric.
-pub struct Metrics {
-    #[cfg(feature = "threads")]
-    histograms: Arc<Mutex<FxHashMap<&'static str, Histogram<u64>>>>,
-    #[cfg(feature = "threads")]
-    worker_handle: Option<JoinHandle<()>>,
-    #[cfg(feature = "threads")]
-    worker_flag: Arc<AtomicBool>,
+#[cfg(feature = "disable")]
+pub struct Span;
+#[cfg(feature = "disable")]
+impl Span {
+    #[inline(always)]
+    pub fn new(_name: &'static str) -> Self {
+        Self
+    }
 
-    #[cfg(not(feature = "threads"))]
-    histograms: RefCell<FxHashMap<&'static str, Histogram<u64>>>,
+    #[inline(always)]
+    pub fn with_category(_name: &'static str, _category: &'static Category) -> Self {
+        Self
+    }
 
-    sigfig: u8,
+    #[inline(always)]
+    pub fn with_options(
+        _name: &'static str,
+        _category: Option<&'static Category>,
+        _sampler: Option<&'static Sampler>,
+    ) -> Self {
+        Self
+    }
+
+    #[inline(always)]
+    pub fn with_callsite(
+        _callsite: &'static Callsite,
+        _category: Option<&'static Category>,
+        _sampler: Option<&'static Sampler>,
+    ) -> Self {
+        Self
+    }
+
+    pub fn current_path() -> Option<String> {
+        None
+    }
 }
 
-impl Metrics {
+/// Identifies a manual span started with `Metrics::start_span`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SpanId(u64);
+impl SpanId {
+    /// The id returned while collection is disabled or suspended. Ending it does nothing.
+    pub const NONE: SpanId = SpanId(0);
 
-    /// Iterate the histograms created. This function accepts a closure of `FnMut(&'static str, &Histogram<u64>)`
-    /// taking the span name and the histogram as arguments.
-    pub fn for_each_histogram<F>(&self, mut f: F)
-    where
-        F: FnMut(&'static str, &Histogram<u64>),
-    {
-        #[cfg(feature = "threads")]
-        {
-            self.histograms
-                .lock()
-                .iter()
-                .for_each(|(name, histogram)| (f)(name, histogram))
-        }
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
 
-        #[cfg(not(feature = "threads"))]
-        {
-            self.flush();
-            self.histograms.borrow()
-                .iter()
-                .for_each(|(name, histogram)| (f)(name, histogram))
+fn new_histogram(sigfig: u8) -> Histogram<u64> {
+    Histogram::new_with_bounds(1, 1_000_000_000, sigfig).unwrap()
+}
+
+/// Aggregated data for a single named span.
+struct SpanData {
+    histogram: Histogram<u64>,
+    game_histogram: Histogram<u64>,
+    calls: u64,
+    /// The estimated total time spent in the span, in nanoseconds.
+    total: u64,
+    allocations: Allocations,
+    /// The call site of the span this one first completed inside, used for the report tree.
+    parent: Option<CallsiteId>,
+}
+impl SpanData {
+    fn new(sigfig: u8) -> Self {
+        Self {
+            histogram: new_histogram(sigfig),
+            game_histogram: new_histogram(sigfig),
+            calls: 0,
+            total: 0,
+            allocations: Allocations::default(),
+            parent: None,
         }
     }
+}
 
-    /// Blocks the current thread until the worker thread has completed flushing the receiver.
-    ///
-    /// # Warning
-    /// In high contention situations, this may block indefinitely. This method is meant to be
-    /// used in the context of a game engine, where execution can be guaranteed to be blocked for
-    /// metric collection.
+/// The maximum number of manual spans kept open. Past it the oldest are discarded, so spans
+/// which are never ended don't accumulate.
+const MAX_OPEN_SPANS: usize = 4096;
+
+/// A manual span which has been started but not yet ended.
+struct OpenSpan {
+    callsite: CallsiteId,
+    parent: Option<CallsiteId>,
+    thread: u64,
+    start: u64,
+    /// The node of the span in the stack tree, under the span active where it started.
+    node: usize,
+}
+
+/// The data accumulated from the event stream, shared by the worker thread and `Metrics`.
+pub(crate) struct Data {
+    /// Span data indexed by `CallsiteId`.
+    spans: Vec<Option<SpanData>>,
+    /// Manual spans by id, and so in the order they were started.
+    open_spans: BTreeMap<SpanId, OpenSpan>,
+    marks: FxHashMap<&'static str, u64>,
+    stacks: StackTree,
+    plots: FxHashMap<&'static str, f64>,
+    counters: FxHashMap<&'static str, u64>,
+    /// Histograms of the values recorded with `Metrics::record`, which resize to fit.
+    values: FxHashMap<&'static str, Histogram<u64>>,
     #[cfg(feature = "threads")]
-    pub fn flush(&self) {
-        while !CHANNEL.channel.1.is_empty() {
-            std::thread::yield_now();
+    sinks: Vec<SinkThread>,
+    #[cfg(not(feature = "threads"))]
+    sinks: Vec<Box<dyn EventSink>>,
+    frame_wall: Histogram<u64>,
+    /// The total wall time of all recorded frames, in nanoseconds.
+    frame_wall_total: u64,
+    frame_sim: Histogram<u64>,
+    /// The ratio of simulation time to wall time over the last frame, used to convert span
+    /// durations to game time.
+    time_scale: f64,
+    frame_stats: FrameStats,
+    hitches: Option<HitchRecorder>,
+    sigfig: u8,
+}
+impl Data {
+    pub(crate) fn new(sigfig: u8) -> Self {
+        Self {
+            spans: Vec::new(),
+            open_spans: BTreeMap::new(),
+            marks: FxHashMap::default(),
+            stacks: StackTree::default(),
+            plots: FxHashMap::default(),
+            counters: FxHashMap::default(),
+            values: FxHashMap::default(),
+            sinks: Vec::new(),
+            frame_wall: new_histogram(sigfig),
+            frame_wall_total: 0,
+            frame_sim: new_histogram(sigfig),
+            time_scale: 1.0,
+            frame_stats: FrameStats::default(),
+            hitches: None,
+            sigfig,
         }
     }
-    #[cfg(not(feature = "threads"))]
-    pub fn flush(&self) {
-        while let Some(event) = CHANNEL.recv() {
-            if let Event::SpanExit { span_name, elapsed } = event {
-                self.histograms
-                    .borrow_mut()
-                    .entry(span_name)
-                    .or_insert_with(
-                        || Histogram::new_with_bounds(1, 1_000_000_000, self.sigfig).unwrap(),
-                    )
-                    .record(elapsed);
-            }
+
+    fn span_mut(&mut self, callsite: CallsiteId) -> &mut SpanData {
+        let index = callsite.index();
+        if index >= self.spans.len() {
+            self.spans.resize_with(index + 1, || None);
         }
+
+        let sigfig = self.sigfig;
+        self.spans[index].get_or_insert_with(|| SpanData::new(sigfig))
     }
 
-    /// Creates a new metrcs instance, initializing metrics and spawning a worker to collect the data.
-    ///
-    /// # Warning
-    /// Any given instance of `Metrics` will globally collect a duplicate of the `Histgram` data. Only
-    /// one instance should be active at a time.
-    #[allow(unused_must_use)]
-    #[cfg(feature = "threads")]
-    pub fn new(sigfig: u8) -> Metrics {
-        let worker_flag = Arc::new(AtomicBool::new(true));
-        let histograms = Arc::new(Mutex::new(FxHashMap::default()));
-
-        let inner_histograms = histograms.clone();
-        let inner_flag = worker_flag.clone();
-        let worker_handle = std::thread::spawn(move || {
-            while inner_flag.load(Ordering::Relaxed) {
-                while let Some(event) = CHANNEL.recv() {
-                    if let Event::SpanExit { span_name, elapsed } = event {
-                        inner_histograms
-                            .lock()
-                            .entry(span_name)
-                            .or_insert_with(
-                                || Histogram::new_with_bounds(1, 1_000_000_000, sigfig).unwrap(),
-                            )
-                            .record(elapsed);
-                    }
-                }
+    /// Iterates the span data of every call site named `name`.
+    fn spans_named<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a SpanData> + 'a {
+        self.spans().filter_map(move |(callsite, span)| {
+            if crate::callsite::callsite(callsite).name() == name {
+                Some(span)
+            } else {
+                None
             }
-        });
+        })
+    }
 
-        CHANNEL.subscribers.fetch_add(1, Ordering::SeqCst);
+    fn spans(&self) -> impl Iterator<Item = (CallsiteId, &SpanData)> {
+        self.spans.iter().enumerate().filter_map(|(index, span)| {
+            span.as_ref()
+                .map(|span| (CallsiteId::from_index(index), span))
+        })
+    }
 
-        Self {
-            histograms,
-            worker_flag,
-            sigfig,
-            worker_handle: Some(worker_handle),
-        }
+    pub(crate) fn for_each_callsite_histogram<F>(&self, mut f: F)
+    where
+        F: FnMut(&'static Callsite, &Histogram<u64>),
+    {
+        self.spans()
+            .for_each(|(callsite, span)| (f)(crate::callsite::callsite(callsite), &span.histogram))
     }
 
-    #[cfg(not(feature = "threads"))]
-    pub fn new(sigfig: u8) -> Metrics {
-        Self {
-            histograms: RefCell::new(FxHashMap::default()),
-            sigfig
+    pub(crate) fn estimated_calls(&self, span_name: &str) -> Option<u64> {
+        self.spans_named(span_name)
+            .map(|span| span.calls)
+            .fold(None, |total, calls| Some(total.unwrap_or(0) + calls))
+    }
+
+    pub(crate) fn allocations(&self, span_name: &str) -> Option<Allocations> {
+        self.spans_named(span_name)
+            .map(|span| span.allocations)
+            .fold(None, |total, allocations| {
+                Some(total.unwrap_or_default() + allocations)
+            })
+    }
+
+    fn flush_sinks(&mut self) {
+        self.sinks.iter_mut().for_each(|sink| sink.flush());
+    }
+
+    pub(crate) fn mark_count(&self, name: &str) -> u64 {
+        self.marks.get(name).copied().unwrap_or(0)
+    }
+
+    pub(crate) fn counter(&self, name: &str) -> Option<u64> {
+        self.counters.get(name).copied()
+    }
+
+    pub(crate) fn report(&self) -> Report {
+        let rows = self
+            .spans()
+            .map(|(callsite, span)| {
+                let histogram = &span.histogram;
+                ReportRow {
+                    callsite,
+                    name: crate::callsite::callsite(callsite).name(),
+                    parent: span.parent,
+                    depth: 0,
+                    calls: span.calls,
+                    total: Duration::from_nanos(span.total),
+                    mean: Duration::from_nanos(histogram.mean() as u64),
+                    p50: Duration::from_nanos(histogram.value_at_quantile(0.5)),
+                    p95: Duration::from_nanos(histogram.value_at_quantile(0.95)),
+                    p99: Duration::from_nanos(histogram.value_at_quantile(0.99)),
+                    max: Duration::from_nanos(histogram.max()),
+                    frame_percent: if self.frame_wall_total > 0 {
+                        Some(span.total as f64 / self.frame_wall_total as f64 * 100.0)
+                    } else {
+                        None
+                    },
+                }
+            })
+            .collect();
+
+        let marks = self
+            .marks
+            .iter()
+            .map(|(name, count)| (*name, *count))
+            .collect();
+        let counters = self
+            .counters
+            .iter()
+            .map(|(name, value)| (*name, *value))
+            .collect();
+        let values = self
+            .values
+            .iter()
+            .map(|(name, histogram)| ReportValue {
+                name,
+                count: histogram.len(),
+                mean: histogram.mean(),
+                p50: histogram.value_at_quantile(0.5),
+                p95: histogram.value_at_quantile(0.95),
+                p99: histogram.value_at_quantile(0.99),
+                max: histogram.max(),
+            })
+            .collect();
+        Report::new(rows, marks, counters, values)
+    }
+
+    pub(crate) fn process(&mut self, event: Event) {
+        self.sinks.iter_mut().for_each(|sink| sink.event(&event));
+
+        match event {
+            Event::SpanEnter {
+                callsite, thread, ..
+            } => self.stacks.enter(thread, callsite),
+            Event::SpanExit {
+                callsite,
+                start,
+                elapsed,
+                weight,
+                thread,
+                depth,
+                parent,
+                allocations,
+            } => {
+                self.stacks.exit(thread, callsite, elapsed);
+
+                let time_scale = self.time_scale;
+                let span = self.span_mut(callsite);
+                span.histogram.saturating_record(elapsed);
+                span.game_histogram
+                    .saturating_record((elapsed as f64 * time_scale) as u64);
+                if span.calls == 0 && parent != Some(callsite) {
+                    span.parent = parent;
+                }
+                span.calls += weight;
+                span.total = span.total.saturating_add(elapsed.saturating_mul(weight));
+                span.allocations += allocations;
+
+                if let Some(hitches) = &mut self.hitches {
+                    hitches.span(TimelineSpan {
+                        callsite,
+                        name: crate::callsite::callsite(callsite).name(),
+                        thread,
+                        depth,
+                        start,
+                        elapsed,
+                    });
+                }
+            }
+            Event::AsyncStart {
+                id,
+                callsite,
+                parent,
+                thread,
+                start,
+            } => {
+                if self.open_spans.len() >= MAX_OPEN_SPANS {
+                    let oldest = *self.open_spans.keys().next().unwrap();
+                    self.open_spans.remove(&oldest);
+                }
+
+                let node = self.stacks.detached(thread, callsite);
+                self.open_spans.insert(
+                    id,
+                    OpenSpan {
+                        callsite,
+                        parent,
+                        thread,
+                        start,
+                        node,
+                    },
+                );
+            }
+            Event::AsyncEnd { id, thread, end } => {
+                let open = match self.open_spans.remove(&id) {
+                    Some(open) => open,
+                    None => return,
+                };
+                let elapsed = end.saturating_sub(open.start);
+                self.stacks.add_self_time(open.node, elapsed);
+
+                let time_scale = self.time_scale;
+                let span = self.span_mut(open.callsite);
+                span.histogram.saturating_record(elapsed);
+                span.game_histogram
+                    .saturating_record((elapsed as f64 * time_scale) as u64);
+                span.calls += 1;
+                span.total = span.total.saturating_add(elapsed);
+
+                if let Some(hitches) = &mut self.hitches {
+                    hitches.async_span(AsyncSpan {
+                        id,
+                        callsite: open.callsite,
+                        name: crate::callsite::callsite(open.callsite).name(),
+                        parent: open
+                            .parent
+                            .map(|parent| crate::callsite::callsite(parent).name()),
+                        start_thread: open.thread,
+                        end_thread: thread,
+                        start: open.start,
+                        elapsed,
+                    });
+                }
+            }
+            Event::Mark {
+                name,
+                thread,
+                time,
+                fields,
+            } => {
+                *self.marks.entry(name).or_insert(0) += 1;
+                self.stacks.mark(thread, name);
+
+                if let Some(hitches) = &mut self.hitches {
+                    hitches.mark(Marker {
+                        name,
+                        thread,
+                        time,
+                        fields,
+                    });
+                }
+            }
+            Event::Plot { name, value, .. } => {
+                self.plots.insert(name, value);
+            }
+            Event::Count { name, value, .. } => {
+                let counter = self.counters.entry(name).or_insert(0);
+                *counter = counter.saturating_add(value);
+            }
+            Event::Record { name, value, .. } => {
+                let sigfig = self.sigfig;
+                let histogram = self
+                    .values
+                    .entry(name)
+                    .or_insert_with(|| Histogram::new(sigfig).unwrap());
+                // Auto resizing histograms only fail to record if they would outgrow memory.
+                histogram.record(value).ok();
+            }
+            Event::FrameEnd {
+                frame, wall, sim, ..
+            } => {
+                if let Some(hitches) = &mut self.hitches {
+                    hitches.end_frame(frame, wall);
+                }
+
+                self.frame_wall.saturating_record(wall);
+                self.frame_wall_total = self.frame_wall_total.saturating_add(wall);
+                self.frame_stats.record_nanos(wall);
+                self.frame_sim.saturating_record(sim);
+                if wall > 0 {
+                    self.time_scale = sim as f64 / wall as f64;
+                }
+            }
+            Event::Log {
+                level,
+                target,
+                message,
+                span_path,
+                thread,
+                time,
+                ..
+            } => {
+                if let Some(hitches) = &mut self.hitches {
+                    hitches.log(TimelineLog {
+                        level,
+                        target,
+                        message,
+                        span_path,
+                        thread,
+                        time,
+                    });
+                }
+            }
         }
     }
 }
-impl Drop for Metrics {
-    fn drop(&mut self) {
-        #[cfg(feature = "threads")]
-            {
-                CHANNEL.subscribers.fetch_sub(1, Ordering::SeqCst);
 
-                self.worker_flag.store(false, Ordering::Relaxed);
-                self.worker_handle.take().unwrap().join().unwrap();
-            }
+/// The metrics struct is used to initialize the metrics communications channels and access the
+/// `Histogram` data for each named metric.
+///
+/// With the `disable` feature, `Metrics` is zero sized and every query sees no data.
+pub struct Metrics {
+    #[cfg(all(feature = "threads", not(feature = "disable")))]
+    data: Arc<Mutex<Data>>,
+    #[cfg(all(feature = "threads", not(feature = "disable")))]
+    worker_handle: Option<JoinHandle<()>>,
+    #[cfg(all(feature = "threads", not(feature = "disable")))]
+    worker_flag: Arc<AtomicBool>,
+
+    #[cfg(all(not(feature = "threads"), not(feature = "disable")))]
+    data: RefCell<Data>,
+    /// The handle of the queue of the thread which created the instance.
+    #[cfg(all(not(feature = "threads"), not(feature = "disable")))]
+    subscribed: Arc<AtomicBool>,
+
+    #[cfg(not(feature = "disable"))]
+    sigfig: u8,
+}
+
+#[cfg(feature = "disable")]
+lazy_static::lazy_static! {
+    /// The empty frame histograms and stats lent by a disabled `Metrics`, created on first use.
+    static ref EMPTY_FRAMES: (Histogram<u64>, Histogram<u64>, FrameStats) =
+        (new_histogram(1), new_histogram(1), FrameStats::new(1));
+}
+
+impl Metrics {
+    /// Returns the current frame number, as advanced by `Metrics::next_frame`.
+    pub fn current_frame() -> u64 {
+        FRAME.load(Ordering::Relaxed)
+    }
+
+    /// Advances the global frame counter, returning the new frame number. This should be called
+    /// once per frame by the game loop. The simulation time of the frame is taken to be its wall
+    /// time; use `Metrics::next_frame_with_delta` when the game runs paused, slowed or sped up.
+    pub fn next_frame(&self) -> u64 {
+        self.end_frame(None)
+    }
+
+    /// Advances the global frame counter like `Metrics::next_frame`, recording `sim_delta` as
+    /// the simulation time which passed during the frame.
+    ///
+    /// The ratio of simulation to wall time of each frame is used to convert the spans of the
+    /// following frame to game time, as reported by `Metrics::for_each_game_histogram`.
+    ///
+    /// # Example
+    /// ```
+    /// use game_metrics::{set_clock_source, Metrics, MockClock};
+    /// use std::time::Duration;
+    ///
+    /// let clock = MockClock::default();
+    /// set_clock_source(clock.clone());
+    ///
+    /// let metrics = Metrics::new(3);
+    /// metrics.next_frame();
+    /// clock.advance(Duration::from_millis(16));
+    /// metrics.next_frame_with_delta(Duration::from_millis(8));
+    ///
+    /// metrics.flush();
+    /// metrics.with_frame_histograms(|wall, sim| {
+    ///     assert!(wall.equivalent(wall.max(), 16_000_000));
+    ///     assert!(sim.equivalent(sim.max(), 8_000_000));
+    /// });
+    /// ```
+    pub fn next_frame_with_delta(&self, sim_delta: Duration) -> u64 {
+        self.end_frame(Some(sim_delta.as_nanos() as u64))
+    }
+
+    #[cfg(not(feature = "disable"))]
+    fn end_frame(&self, sim: Option<u64>) -> u64 {
+        let frame = FRAME.fetch_add(1, Ordering::Relaxed);
+        let now = crate::clock::now();
+        let start = FRAME_START.swap(now, Ordering::Relaxed);
+
+        if start != u64::MAX && !SUSPENDED.load(Ordering::Relaxed) {
+            let wall = now.saturating_sub(start);
+            dispatch(Event::FrameEnd {
+                frame,
+                time: now,
+                wall,
+                sim: sim.unwrap_or(wall),
+            });
+        }
+
+        frame + 1
+    }
+
+    #[cfg(feature = "disable")]
+    #[inline(always)]
+    fn end_frame(&self, _sim: Option<u64>) -> u64 {
+        0
+    }
+
+    /// Starts a manual span named `name`, which is timed until `Metrics::end_span` is called
+    /// with the returned id. Unlike `Span`, it is not tied to a lexical scope or a thread, so it
+    /// can time asset loads, GPU readbacks or job chains which finish on another thread. The
+    /// span is linked to the innermost `Span` active on the calling thread.
+    ///
+    /// Manual spans are aggregated into the histogram of their name, and appear in frame
+    /// timelines as `AsyncSpan`s in the frame they ended.
+    ///
+    /// Without the `threads` feature, spans must be ended on the thread owning `Metrics`. At
+    /// most 4096 spans are kept open, past which the oldest are discarded.
+    ///
+    /// # Example
+    /// ```
+    /// use game_metrics::{set_clock_source, Metrics, MockClock};
+    /// use std::time::Duration;
+    ///
+    /// let clock = MockClock::default();
+    /// set_clock_source(clock.clone());
+    ///
+    /// let metrics = Metrics::new(3);
+    /// let id = Metrics::start_span("load_texture");
+    /// clock.advance(Duration::from_millis(5));
+    /// Metrics::end_span(id);
+    ///
+    /// metrics.flush();
+    /// metrics.for_each_histogram(|name, h| {
+    ///     assert_eq!(name, "load_texture");
+    ///     assert!(h.equivalent(h.max(), 5_000_000));
+    /// });
+    /// ```
+    #[cfg(not(feature = "disable"))]
+    pub fn start_span(name: &'static str) -> SpanId {
+        Self::start_span_with(|| crate::callsite::by_name(name).id())
+    }
+
+    /// Starts a manual span for a static `Callsite`, like `Metrics::start_span`.
+    #[cfg(not(feature = "disable"))]
+    pub(crate) fn start_callsite_span(callsite: &'static Callsite) -> SpanId {
+        Self::start_span_with(|| callsite.id())
+    }
+
+    #[cfg(not(feature = "disable"))]
+    fn start_span_with(callsite: impl FnOnce() -> CallsiteId) -> SpanId {
+        if SUSPENDED.load(Ordering::Relaxed) || !crate::category::is_enabled() {
+            return SpanId::NONE;
+        }
+
+        let id = SpanId(NEXT_SPAN_ID.fetch_add(1, Ordering::Relaxed));
+        dispatch(Event::AsyncStart {
+            id,
+            callsite: crate::allocation::untracked(callsite),
+            parent: SPAN_STACK
+                .try_with(|stack| stack.borrow().last().map(|(_, callsite)| *callsite))
+                .ok()
+                .flatten(),
+            thread: thread_index(),
+            start: crate::clock::now(),
+        });
+        id
+    }
+
+    /// Ends a manual span started with `Metrics::start_span`. May be called from any thread.
+    #[cfg(not(feature = "disable"))]
+    pub fn end_span(id: SpanId) {
+        if id == SpanId::NONE {
+            return;
+        }
+
+        dispatch(Event::AsyncEnd {
+            id,
+            thread: thread_index(),
+            end: crate::clock::now(),
+        });
+    }
+
+    #[cfg(feature = "disable")]
+    #[inline(always)]
+    pub fn start_span(_name: &'static str) -> SpanId {
+        SpanId::NONE
+    }
+
+    #[cfg(feature = "disable")]
+    #[inline(always)]
+    pub(crate) fn start_callsite_span(_callsite: &'static Callsite) -> SpanId {
+        SpanId::NONE
+    }
+
+    #[cfg(feature = "disable")]
+    #[inline(always)]
+    pub fn end_span(_id: SpanId) {}
+
+    /// Records an instant marker named `name`. The fields are only built when recording is
+    /// enabled. Usually called through `mark!`.
+    ///
+    /// Markers are counted by name, and appear in frame timelines in the frame they were
+    /// recorded, so hitches can be lined up with gameplay events.
+    ///
+    /// # Example
+    /// ```
+    /// use game_metrics::{mark, HitchCapture, Metrics};
+    /// use std::time::Duration;
+    ///
+    /// let metrics = Metrics::new(1);
+    /// metrics.set_hitch_capture(Some(HitchCapture {
+    ///     threshold: Duration::from_secs(0),
+    ///     ..HitchCapture::default()
+    /// }));
+    ///
+    /// metrics.next_frame();
+    /// let collected = 3;
+    /// mark!("gc_triggered", count = collected);
+    /// metrics.next_frame();
+    ///
+    /// metrics.flush();
+    /// assert_eq!(metrics.mark_count("gc_triggered"), 1);
+    /// metrics.for_each_hitch(|hitch| {
+    ///     let marker = &hitch.timeline[0].marks[0];
+    ///     assert_eq!(marker.fields, vec![("count", "3".to_owned())]);
+    /// });
+    /// ```
+    #[cfg(not(feature = "disable"))]
+    pub fn mark_with<F>(name: &'static str, fields: F)
+    where
+        F: FnOnce() -> Vec<(&'static str, String)>,
+    {
+        if SUSPENDED.load(Ordering::Relaxed) || !crate::category::is_enabled() {
+            return;
+        }
+
+        dispatch(Event::Mark {
+            name,
+            thread: THREAD_INDEX.with(|index| *index),
+            time: crate::clock::now(),
+            fields: fields(),
+        });
+    }
+
+    #[cfg(feature = "disable")]
+    #[inline(always)]
+    pub fn mark_with<F>(_name: &'static str, _fields: F)
+    where
+        F: FnOnce() -> Vec<(&'static str, String)>,
+    {
+    }
+
+    /// Plots `value` under `name`, e.g. a gauge such as the entity count or memory in use, which
+    /// external profilers can graph over time.
+    #[cfg(not(feature = "disable"))]
+    pub fn plot(name: &'static str, value: f64) {
+        if SUSPENDED.load(Ordering::Relaxed) || !crate::category::is_enabled() {
+            return;
+        }
+
+        dispatch(Event::Plot {
+            name,
+            value,
+            time: crate::clock::now(),
+        });
+    }
+
+    #[cfg(feature = "disable")]
+    #[inline(always)]
+    pub fn plot(_name: &'static str, _value: f64) {}
+
+    /// Adds `value` to the counter `name`, e.g. the number of packets sent.
+    #[cfg(not(feature = "disable"))]
+    pub fn count(name: &'static str, value: u64) {
+        if SUSPENDED.load(Ordering::Relaxed) || !crate::category::is_enabled() {
+            return;
+        }
+
+        dispatch(Event::Count {
+            name,
+            value,
+            time: crate::clock::now(),
+        });
+    }
+
+    #[cfg(feature = "disable")]
+    #[inline(always)]
+    pub fn count(_name: &'static str, _value: u64) {}
+
+    /// Records `value` in the histogram `name`, for distributions other than span durations,
+    /// e.g. packet sizes or the latency of a remote call in nanoseconds.
+    #[cfg(not(feature = "disable"))]
+    pub fn record(name: &'static str, value: u64) {
+        if SUSPENDED.load(Ordering::Relaxed) || !crate::category::is_enabled() {
+            return;
+        }
+
+        dispatch(Event::Record {
+            name,
+            value,
+            time: crate::clock::now(),
+        });
+    }
+
+    #[cfg(feature = "disable")]
+    #[inline(always)]
+    pub fn record(_name: &'static str, _value: u64) {}
+
+    /// Returns the total of the counter `name`, or `None` if it was never incremented.
+    #[cfg(not(feature = "disable"))]
+    pub fn counter(&self, name: &str) -> Option<u64> {
+        self.with_data(|data| data.counter(name))
+    }
+
+    #[cfg(feature = "disable")]
+    #[inline(always)]
+    pub fn counter(&self, _name: &str) -> Option<u64> {
+        None
+    }
+
+    /// Iterate the histograms of values recorded with `Metrics::record`, taking the name and the
+    /// histogram as arguments.
+    #[cfg(not(feature = "disable"))]
+    pub fn for_each_value_histogram<F>(&self, mut f: F)
+    where
+        F: FnMut(&'static str, &Histogram<u64>),
+    {
+        self.with_data(|data| {
+            data.values
+                .iter()
+                .for_each(|(name, histogram)| (f)(name, histogram))
+        })
+    }
+
+    #[cfg(feature = "disable")]
+    #[inline(always)]
+    pub fn for_each_value_histogram<F>(&self, _f: F)
+    where
+        F: FnMut(&'static str, &Histogram<u64>),
+    {
+    }
+
+    /// Returns the last value plotted under `name`.
+    #[cfg(not(feature = "disable"))]
+    pub fn plot_value(&self, name: &str) -> Option<f64> {
+        self.with_data(|data| data.plots.get(name).copied())
+    }
+
+    #[cfg(feature = "disable")]
+    #[inline(always)]
+    pub fn plot_value(&self, _name: &str) -> Option<f64> {
+        None
+    }
+
+    /// Adds a sink which receives every event processed from now on. The sink runs on a thread
+    /// of its own, and events are dropped for it while 65536 of them are waiting.
+    #[cfg(all(feature = "threads", not(feature = "disable")))]
+    pub fn add_sink<S: EventSink + 'static>(&self, sink: S) {
+        self.with_data_mut(|data| data.sinks.push(SinkThread::spawn(Box::new(sink))));
+    }
+
+    /// Adds a sink which receives every event processed from now on, on the owning thread as
+    /// `Metrics::flush` processes them.
+    #[cfg(all(not(feature = "threads"), not(feature = "disable")))]
+    pub fn add_sink<S: EventSink + 'static>(&self, sink: S) {
+        self.with_data_mut(|data| data.sinks.push(Box::new(sink)));
+    }
+
+    /// With the `disable` feature, the sink is dropped right away, as no events are processed.
+    #[cfg(feature = "disable")]
+    #[inline(always)]
+    pub fn add_sink<S: EventSink + 'static>(&self, sink: S) {
+        drop(sink);
+    }
+
+    /// Returns the number of markers named `name` recorded so far.
+    #[cfg(not(feature = "disable"))]
+    pub fn mark_count(&self, name: &str) -> u64 {
+        self.with_data(|data| data.mark_count(name))
+    }
+
+    #[cfg(feature = "disable")]
+    #[inline(always)]
+    pub fn mark_count(&self, _name: &str) -> u64 {
+        0
+    }
+
+    /// Suspends recording, e.g. while the game is paused or on a loading screen. Spans are
+    /// inert and frames are not recorded until `Metrics::resume` is called, so these frames
+    /// don't pollute the gameplay histograms.
+    pub fn suspend(&self) {
+        SUSPENDED.store(true, Ordering::Relaxed);
+    }
+
+    /// Resumes recording after `Metrics::suspend`.
+    pub fn resume(&self) {
+        SUSPENDED.store(false, Ordering::Relaxed);
+    }
+
+    /// Returns true if recording is suspended.
+    pub fn is_suspended(&self) -> bool {
+        SUSPENDED.load(Ordering::Relaxed)
+    }
+
+    /// Enables hitch capture with the given settings, or disables it with `None`. Enabling hitch
+    /// capture discards any previously captured hitches.
+    #[cfg(not(feature = "disable"))]
+    pub fn set_hitch_capture(&self, settings: Option<HitchCapture>) {
+        self.with_data_mut(|data| data.hitches = settings.map(HitchRecorder::new));
+    }
+
+    #[cfg(feature = "disable")]
+    #[inline(always)]
+    pub fn set_hitch_capture(&self, _settings: Option<HitchCapture>) {}
+
+    /// Iterate the captured hitches, in no particular order.
+    #[cfg(not(feature = "disable"))]
+    pub fn for_each_hitch<F>(&self, mut f: F)
+    where
+        F: FnMut(&Hitch),
+    {
+        self.with_data(|data| {
+            if let Some(hitches) = &data.hitches {
+                hitches.hitches().iter().for_each(&mut f);
+            }
+        })
+    }
+
+    #[cfg(feature = "disable")]
+    #[inline(always)]
+    pub fn for_each_hitch<F>(&self, _f: F)
+    where
+        F: FnMut(&Hitch),
+    {
+    }
+
+    /// Removes and returns the captured hitches.
+    #[cfg(not(feature = "disable"))]
+    pub fn take_hitches(&self) -> Vec<Hitch> {
+        self.with_data_mut(|data| {
+            data.hitches
+                .as_mut()
+                .map(HitchRecorder::take_hitches)
+                .unwrap_or_default()
+        })
+    }
+
+    #[cfg(feature = "disable")]
+    #[inline(always)]
+    pub fn take_hitches(&self) -> Vec<Hitch> {
+        Vec::new()
+    }
+
+    #[cfg(not(feature = "disable"))]
+    fn with_data_mut<R>(&self, f: impl FnOnce(&mut Data) -> R) -> R {
+        #[cfg(feature = "threads")]
+        {
+            f(&mut self.data.lock())
+        }
+
+        #[cfg(not(feature = "threads"))]
+        {
+            self.flush();
+            f(&mut self.data.borrow_mut())
+        }
+    }
+
+    #[cfg(not(feature = "disable"))]
+    fn with_data<R>(&self, f: impl FnOnce(&Data) -> R) -> R {
+        #[cfg(feature = "threads")]
+        {
+            f(&self.data.lock())
+        }
+
+        #[cfg(not(feature = "threads"))]
+        {
+            self.flush();
+            f(&self.data.borrow())
+        }
+    }
+
+    /// Iterate the histograms created. This function accepts a closure of `FnMut(&'static str, &Histogram<u64>)`
+    /// taking the span name and the histogram as arguments.
+    ///
+    /// For sampled spans, the histogram holds only the sampled calls; see `Metrics::estimated_calls`
+    /// for the estimated true call count.
+    ///
+    /// Histograms are kept per `Callsite`, so spans of the same name at different call sites are
+    /// iterated separately.
+    pub fn for_each_histogram<F>(&self, mut f: F)
+    where
+        F: FnMut(&'static str, &Histogram<u64>),
+    {
+        self.for_each_callsite_histogram(|callsite, histogram| f(callsite.name(), histogram))
+    }
+
+    /// Iterate the histograms created along with the `Callsite` of each, in `CallsiteId` order.
+    #[cfg(not(feature = "disable"))]
+    pub fn for_each_callsite_histogram<F>(&self, f: F)
+    where
+        F: FnMut(&'static Callsite, &Histogram<u64>),
+    {
+        self.with_data(|data| data.for_each_callsite_histogram(f))
+    }
+
+    #[cfg(feature = "disable")]
+    #[inline(always)]
+    pub fn for_each_callsite_histogram<F>(&self, _f: F)
+    where
+        F: FnMut(&'static Callsite, &Histogram<u64>),
+    {
+    }
+
+    /// Iterate the game time histograms of each span, like `Metrics::for_each_histogram`. Span
+    /// durations are scaled by the ratio of simulation to wall time of the previous frame.
+    #[cfg(not(feature = "disable"))]
+    pub fn for_each_game_histogram<F>(&self, mut f: F)
+    where
+        F: FnMut(&'static str, &Histogram<u64>),
+    {
+        self.with_data(|data| {
+            data.spans().for_each(|(callsite, span)| {
+                (f)(
+                    crate::callsite::callsite(callsite).name(),
+                    &span.game_histogram,
+                )
+            })
+        })
+    }
+
+    #[cfg(feature = "disable")]
+    #[inline(always)]
+    pub fn for_each_game_histogram<F>(&self, _f: F)
+    where
+        F: FnMut(&'static str, &Histogram<u64>),
+    {
+    }
+
+    /// Calls `f` with the histograms of frame wall time and frame simulation time, in that order.
+    #[cfg(not(feature = "disable"))]
+    pub fn with_frame_histograms<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&Histogram<u64>, &Histogram<u64>) -> R,
+    {
+        self.with_data(|data| f(&data.frame_wall, &data.frame_sim))
+    }
+
+    #[cfg(feature = "disable")]
+    #[inline(always)]
+    pub fn with_frame_histograms<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&Histogram<u64>, &Histogram<u64>) -> R,
+    {
+        f(&EMPTY_FRAMES.0, &EMPTY_FRAMES.1)
+    }
+
+    /// Calls `f` with the `FrameStats` fed by `Metrics::next_frame`, which keep the wall time
+    /// history of the last `FrameStats::DEFAULT_HISTORY_LEN` frames.
+    #[cfg(not(feature = "disable"))]
+    pub fn with_frame_stats<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&FrameStats) -> R,
+    {
+        self.with_data(|data| f(&data.frame_stats))
+    }
+
+    #[cfg(feature = "disable")]
+    #[inline(always)]
+    pub fn with_frame_stats<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&FrameStats) -> R,
+    {
+        f(&EMPTY_FRAMES.2)
+    }
+
+    /// Writes the self time of every stack of spans in the folded stack format read by
+    /// `flamegraph.pl` and `inferno`, e.g. `frame;update;physics 12345`, in nanoseconds.
+    #[cfg(not(feature = "disable"))]
+    pub fn write_folded<W: std::io::Write>(&self, writer: W) -> std::io::Result<()> {
+        self.with_data(|data| data.stacks.write_folded(writer))
+    }
+
+    #[cfg(feature = "disable")]
+    #[inline(always)]
+    pub fn write_folded<W: std::io::Write>(&self, _writer: W) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    /// Writes the number of markers recorded by `mark!` under every stack of spans in the
+    /// folded stack format, e.g. `frame;update;gc_triggered 3`.
+    #[cfg(not(feature = "disable"))]
+    pub fn write_folded_marks<W: std::io::Write>(&self, writer: W) -> std::io::Result<()> {
+        self.with_data(|data| data.stacks.write_folded_marks(writer))
+    }
+
+    #[cfg(feature = "disable")]
+    #[inline(always)]
+    pub fn write_folded_marks<W: std::io::Write>(&self, _writer: W) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    /// Summarizes every span in a `Report`, which displays as an aligned table.
+    #[cfg(not(feature = "disable"))]
+    pub fn report(&self) -> Report {
+        self.with_data(Data::report)
+    }
+
+    #[cfg(feature = "disable")]
+    #[inline(always)]
+    pub fn report(&self) -> Report {
+        Report::new(Vec::new(), Vec::new(), Vec::new(), Vec::new())
+    }
+
+    /// Returns the estimated number of times the span `span_name` was entered. For spans which
+    /// are not sampled, this is the exact call count.
+    #[cfg(not(feature = "disable"))]
+    pub fn estimated_calls(&self, span_name: &str) -> Option<u64> {
+        self.with_data(|data| data.estimated_calls(span_name))
+    }
+
+    #[cfg(feature = "disable")]
+    #[inline(always)]
+    pub fn estimated_calls(&self, _span_name: &str) -> Option<u64> {
+        None
+    }
+
+    /// Returns the total allocations made directly within the span `span_name`. Allocations are
+    /// only tracked with the `alloc` feature and `TrackingAllocator` installed as the global
+    /// allocator.
+    #[cfg(not(feature = "disable"))]
+    pub fn allocations(&self, span_name: &str) -> Option<Allocations> {
+        self.with_data(|data| data.allocations(span_name))
+    }
+
+    #[cfg(feature = "disable")]
+    #[inline(always)]
+    pub fn allocations(&self, _span_name: &str) -> Option<Allocations> {
+        None
+    }
+
+    /// Returns the number of events discarded because they were sent from a thread other than
+    /// the one which created the `Metrics` instance.
+    #[cfg(all(not(feature = "threads"), not(feature = "disable")))]
+    pub fn dropped_events(&self) -> u64 {
+        CHANNEL.dropped.load(Ordering::Relaxed)
+    }
+
+    #[cfg(all(not(feature = "threads"), feature = "disable"))]
+    pub fn dropped_events(&self) -> u64 {
+        0
+    }
+
+    /// Blocks the current thread until the worker thread has completed flushing the receiver.
+    ///
+    /// # Warning
+    /// In high contention situations, this may block indefinitely. This method is meant to be
+    /// used in the context of a game engine, where execution can be guaranteed to be blocked for
+    /// metric collection.
+    #[cfg(all(feature = "threads", not(feature = "disable")))]
+    pub fn flush(&self) {
+        while CHANNEL.pending.load(Ordering::SeqCst) > 0 {
+            std::thread::yield_now();
+        }
+        self.data.lock().flush_sinks();
+    }
+    #[cfg(all(not(feature = "threads"), not(feature = "disable")))]
+    pub fn flush(&self) {
+        let mut data = self.data.borrow_mut();
+        while let Some(event) = CHANNEL.recv() {
+            data.process(event);
+        }
+        data.flush_sinks();
+    }
+
+    #[cfg(feature = "disable")]
+    #[inline(always)]
+    pub fn flush(&self) {}
+
+    /// Creates a new metrcs instance, initializing metrics and spawning a worker to collect the data.
+    ///
+    /// # Warning
+    /// Any given instance of `Metrics` will globally collect a duplicate of the `Histgram` data. Only
+    /// one instance should be active at a time.
+    ///
+    /// With the `disable` feature, no worker is spawned and no data is ever collected.
+    #[cfg(all(feature = "threads", not(feature = "disable")))]
+    pub fn new(sigfig: u8) -> Metrics {
+        let worker_flag = Arc::new(AtomicBool::new(true));
+        let data = Arc::new(Mutex::new(Data::new(sigfig)));
+
+        CHANNEL.subscribers.fetch_add(1, Ordering::SeqCst);
+        let worker_handle = Some(spawn_worker(data.clone(), worker_flag.clone()));
+
+        Self {
+            data,
+            worker_flag,
+            sigfig,
+            worker_handle,
+        }
+    }
+
+    /// Creates a new metrics instance, collecting events from spans on the calling thread only.
+    /// Events from other threads are discarded and counted in `Metrics::dropped_events`.
+    #[cfg(all(not(feature = "threads"), not(feature = "disable")))]
+    pub fn new(sigfig: u8) -> Metrics {
+        Self {
+            data: RefCell::new(Data::new(sigfig)),
+            subscribed: CHANNEL.subscribe(),
+            sigfig,
+        }
+    }
+
+    #[cfg(feature = "disable")]
+    #[inline(always)]
+    pub fn new(_sigfig: u8) -> Metrics {
+        Self {}
+    }
+}
+impl std::fmt::Display for Metrics {
+    /// Displays the table of `Metrics::report`.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.report().fmt(f)
+    }
+}
+
+#[cfg(all(feature = "threads", not(feature = "disable")))]
+fn spawn_worker(data: Arc<Mutex<Data>>, worker_flag: Arc<AtomicBool>) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        while worker_flag.load(Ordering::Relaxed) {
+            while let Some(event) = CHANNEL.recv() {
+                data.lock().process(event);
+                CHANNEL.pending.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+    })
+}
+
+#[cfg(not(feature = "disable"))]
+impl Drop for Metrics {
+    fn drop(&mut self) {
+        #[cfg(feature = "threads")]
+        {
+            if let Some(worker_handle) = self.worker_handle.take() {
+                CHANNEL.subscribers.fetch_sub(1, Ordering::SeqCst);
+
+                self.worker_flag.store(false, Ordering::Relaxed);
+                worker_handle.join().unwrap();
+            }
+        }
+
+        #[cfg(not(feature = "threads"))]
+        CHANNEL.unsubscribe(&self.subscribed);
     }
 }
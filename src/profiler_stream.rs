@@ -0,0 +1,474 @@
+//! A live event stream for external timeline viewers.
+//!
+//! With the `profiler-stream` feature, `ProfilerBridge` is an `EventSink` which streams span
+//! zones, manual spans, markers, log messages, plots and frame marks over TCP, so one set of
+//! `#[instrument]` annotations feeds both the histograms of this crate and a timeline viewer.
+//! Counters are plotted as their running total, and values from `Metrics::record` as they are
+//! recorded.
+//!
+//! This is not Tracy support: the stream is this crate's own format, and Tracy or any other
+//! existing profiler can't read it. A viewer needs a client for it. Each call site is announced
+//! once as a `ProfilerMessage::SourceLocation`, after which zones refer to it by id. Every
+//! message is a type byte followed by little endian fields, with strings prefixed by their `u32`
+//! length. `ProfilerMessage::read_from` decodes the stream, e.g. in such a client or in a mock
+//! server in tests.
+//!
+//! # Example
+//! ```
+//! use game_metrics::{scope, Metrics, ProfilerBridge, ProfilerMessage};
+//! use std::{io::Read, net::TcpListener};
+//!
+//! let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+//! let metrics = Metrics::new(1);
+//! metrics.add_sink(ProfilerBridge::connect(listener.local_addr().unwrap()).unwrap());
+//! let (mut server, _) = listener.accept().unwrap();
+//!
+//! {
+//!     scope!("update");
+//!     Metrics::plot("entities", 42.0);
+//! }
+//! metrics.flush();
+//!
+//! let mut handshake = [0; 8];
+//! server.read_exact(&mut handshake).unwrap();
+//! assert_eq!(&handshake, ProfilerBridge::HANDSHAKE);
+//!
+//! let mut next = || ProfilerMessage::read_from(&mut server).unwrap();
+//! match next() {
+//!     ProfilerMessage::SourceLocation { name, line, .. } => {
+//!         assert_eq!(name, "update");
+//!         assert!(line > 0);
+//!     }
+//!     message => panic!("unexpected {:?}", message),
+//! }
+//! assert!(matches!(next(), ProfilerMessage::ZoneBegin { .. }));
+//! match next() {
+//!     ProfilerMessage::Plot { name, value, .. } => {
+//!         assert_eq!((name.as_str(), value), ("entities", 42.0));
+//!     }
+//!     message => panic!("unexpected {:?}", message),
+//! }
+//! assert!(matches!(next(), ProfilerMessage::ZoneEnd { .. }));
+//! ```
+
+use crate::{
+    callsite::CallsiteId,
+    metrics::{Event, EventSink},
+};
+use fxhash::FxHashMap;
+use std::{
+    io::{self, BufWriter, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+};
+
+const SOURCE_LOCATION: u8 = 0;
+const ZONE_BEGIN: u8 = 1;
+const ZONE_END: u8 = 2;
+const MESSAGE: u8 = 3;
+const PLOT: u8 = 4;
+const FRAME_MARK: u8 = 5;
+const ASYNC_BEGIN: u8 = 6;
+const ASYNC_END: u8 = 7;
+
+/// A message of the profiler stream.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProfilerMessage {
+    /// Announces a call site, sent before the first zone referring to it.
+    SourceLocation {
+        id: u32,
+        name: String,
+        module_path: String,
+        file: String,
+        line: u32,
+    },
+    ZoneBegin {
+        thread: u64,
+        time: u64,
+        source_location: u32,
+    },
+    /// Ends the innermost zone of `thread`.
+    ZoneEnd {
+        thread: u64,
+        time: u64,
+    },
+    /// A marker or log message.
+    Message {
+        thread: u64,
+        time: u64,
+        text: String,
+    },
+    Plot {
+        time: u64,
+        name: String,
+        value: f64,
+    },
+    FrameMark {
+        time: u64,
+        frame: u64,
+    },
+    /// Starts a manual span, which may end on another thread.
+    AsyncBegin {
+        id: u64,
+        thread: u64,
+        time: u64,
+        source_location: u32,
+        /// The source location of the zone active on `thread` when the span started.
+        parent: Option<u32>,
+    },
+    AsyncEnd {
+        id: u64,
+        thread: u64,
+        time: u64,
+    },
+}
+impl ProfilerMessage {
+    /// Writes the message in the stream format.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        match self {
+            ProfilerMessage::SourceLocation {
+                id,
+                name,
+                module_path,
+                file,
+                line,
+            } => {
+                writer.write_all(&[SOURCE_LOCATION])?;
+                writer.write_all(&id.to_le_bytes())?;
+                write_str(writer, name)?;
+                write_str(writer, module_path)?;
+                write_str(writer, file)?;
+                writer.write_all(&line.to_le_bytes())
+            }
+            ProfilerMessage::ZoneBegin {
+                thread,
+                time,
+                source_location,
+            } => {
+                writer.write_all(&[ZONE_BEGIN])?;
+                writer.write_all(&thread.to_le_bytes())?;
+                writer.write_all(&time.to_le_bytes())?;
+                writer.write_all(&source_location.to_le_bytes())
+            }
+            ProfilerMessage::ZoneEnd { thread, time } => {
+                writer.write_all(&[ZONE_END])?;
+                writer.write_all(&thread.to_le_bytes())?;
+                writer.write_all(&time.to_le_bytes())
+            }
+            ProfilerMessage::Message { thread, time, text } => {
+                writer.write_all(&[MESSAGE])?;
+                writer.write_all(&thread.to_le_bytes())?;
+                writer.write_all(&time.to_le_bytes())?;
+                write_str(writer, text)
+            }
+            ProfilerMessage::Plot { time, name, value } => {
+                writer.write_all(&[PLOT])?;
+                writer.write_all(&time.to_le_bytes())?;
+                write_str(writer, name)?;
+                writer.write_all(&value.to_le_bytes())
+            }
+            ProfilerMessage::FrameMark { time, frame } => {
+                writer.write_all(&[FRAME_MARK])?;
+                writer.write_all(&time.to_le_bytes())?;
+                writer.write_all(&frame.to_le_bytes())
+            }
+            ProfilerMessage::AsyncBegin {
+                id,
+                thread,
+                time,
+                source_location,
+                parent,
+            } => {
+                writer.write_all(&[ASYNC_BEGIN])?;
+                writer.write_all(&id.to_le_bytes())?;
+                writer.write_all(&thread.to_le_bytes())?;
+                writer.write_all(&time.to_le_bytes())?;
+                writer.write_all(&source_location.to_le_bytes())?;
+                match parent {
+                    Some(parent) => {
+                        writer.write_all(&[1])?;
+                        writer.write_all(&parent.to_le_bytes())
+                    }
+                    None => writer.write_all(&[0]),
+                }
+            }
+            ProfilerMessage::AsyncEnd { id, thread, time } => {
+                writer.write_all(&[ASYNC_END])?;
+                writer.write_all(&id.to_le_bytes())?;
+                writer.write_all(&thread.to_le_bytes())?;
+                writer.write_all(&time.to_le_bytes())
+            }
+        }
+    }
+
+    /// Reads a message in the stream format.
+    pub fn read_from<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let mut kind = [0; 1];
+        reader.read_exact(&mut kind)?;
+
+        Ok(match kind[0] {
+            SOURCE_LOCATION => ProfilerMessage::SourceLocation {
+                id: read_u32(reader)?,
+                name: read_str(reader)?,
+                module_path: read_str(reader)?,
+                file: read_str(reader)?,
+                line: read_u32(reader)?,
+            },
+            ZONE_BEGIN => ProfilerMessage::ZoneBegin {
+                thread: read_u64(reader)?,
+                time: read_u64(reader)?,
+                source_location: read_u32(reader)?,
+            },
+            ZONE_END => ProfilerMessage::ZoneEnd {
+                thread: read_u64(reader)?,
+                time: read_u64(reader)?,
+            },
+            MESSAGE => ProfilerMessage::Message {
+                thread: read_u64(reader)?,
+                time: read_u64(reader)?,
+                text: read_str(reader)?,
+            },
+            PLOT => ProfilerMessage::Plot {
+                time: read_u64(reader)?,
+                name: read_str(reader)?,
+                value: f64::from_bits(read_u64(reader)?),
+            },
+            FRAME_MARK => ProfilerMessage::FrameMark {
+                time: read_u64(reader)?,
+                frame: read_u64(reader)?,
+            },
+            ASYNC_BEGIN => ProfilerMessage::AsyncBegin {
+                id: read_u64(reader)?,
+                thread: read_u64(reader)?,
+                time: read_u64(reader)?,
+                source_location: read_u32(reader)?,
+                parent: match read_u8(reader)? {
+                    0 => None,
+                    _ => Some(read_u32(reader)?),
+                },
+            },
+            ASYNC_END => ProfilerMessage::AsyncEnd {
+                id: read_u64(reader)?,
+                thread: read_u64(reader)?,
+                time: read_u64(reader)?,
+            },
+            kind => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unknown message type {}", kind),
+                ))
+            }
+        })
+    }
+}
+
+fn write_str<W: Write>(writer: &mut W, value: &str) -> io::Result<()> {
+    writer.write_all(&(value.len() as u32).to_le_bytes())?;
+    writer.write_all(value.as_bytes())
+}
+
+fn read_u8<R: Read>(reader: &mut R) -> io::Result<u8> {
+    let mut bytes = [0; 1];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes[0])
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_str<R: Read>(reader: &mut R) -> io::Result<String> {
+    let mut bytes = vec![0; read_u32(reader)? as usize];
+    reader.read_exact(&mut bytes)?;
+    String::from_utf8(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// An `EventSink` streaming events to a profiler over TCP. The connection is dropped on the
+/// first write error.
+pub struct ProfilerBridge {
+    stream: Option<BufWriter<TcpStream>>,
+    /// Whether the source location of each call site, indexed by `CallsiteId`, has been sent.
+    announced: Vec<bool>,
+    /// The running total of each counter.
+    counters: FxHashMap<&'static str, u64>,
+}
+impl ProfilerBridge {
+    /// Sent once when the connection is established.
+    pub const HANDSHAKE: &'static [u8; 8] = b"GMPROF01";
+
+    /// Connects to the profiler or relay listening at `addr`.
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+
+        let mut stream = BufWriter::new(stream);
+        stream.write_all(Self::HANDSHAKE)?;
+
+        Ok(Self {
+            stream: Some(stream),
+            announced: Vec::new(),
+            counters: FxHashMap::default(),
+        })
+    }
+
+    /// Returns whether the connection is still open.
+    pub fn is_connected(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    /// Pushes the source location of `callsite` if it hasn't been sent yet, returning its id.
+    fn announce(&mut self, callsite: CallsiteId, messages: &mut Vec<ProfilerMessage>) -> u32 {
+        let index = callsite.index();
+        if index >= self.announced.len() {
+            self.announced.resize(index + 1, false);
+        }
+        if !self.announced[index] {
+            self.announced[index] = true;
+            let site = crate::callsite::callsite(callsite);
+            messages.push(ProfilerMessage::SourceLocation {
+                id: index as u32,
+                name: site.name().to_owned(),
+                module_path: site.module_path().to_owned(),
+                file: site.file().to_owned(),
+                line: site.line(),
+            });
+        }
+        index as u32
+    }
+
+    fn messages(&mut self, event: &Event) -> Vec<ProfilerMessage> {
+        match event {
+            Event::SpanEnter {
+                callsite,
+                thread,
+                time,
+            } => {
+                let mut messages = Vec::with_capacity(2);
+                let source_location = self.announce(*callsite, &mut messages);
+                messages.push(ProfilerMessage::ZoneBegin {
+                    thread: *thread,
+                    time: *time,
+                    source_location,
+                });
+                messages
+            }
+            Event::SpanExit {
+                start,
+                elapsed,
+                thread,
+                ..
+            } => vec![ProfilerMessage::ZoneEnd {
+                thread: *thread,
+                time: start + elapsed,
+            }],
+            Event::AsyncStart {
+                id,
+                callsite,
+                parent,
+                thread,
+                start,
+            } => {
+                let mut messages = Vec::with_capacity(3);
+                let source_location = self.announce(*callsite, &mut messages);
+                let parent = parent.map(|parent| self.announce(parent, &mut messages));
+                messages.push(ProfilerMessage::AsyncBegin {
+                    id: id.as_u64(),
+                    thread: *thread,
+                    time: *start,
+                    source_location,
+                    parent,
+                });
+                messages
+            }
+            Event::AsyncEnd { id, thread, end } => vec![ProfilerMessage::AsyncEnd {
+                id: id.as_u64(),
+                thread: *thread,
+                time: *end,
+            }],
+            Event::Mark {
+                name,
+                thread,
+                time,
+                fields,
+            } => {
+                let mut text = (*name).to_owned();
+                for (key, value) in fields {
+                    text.push_str(&format!(" {}={}", key, value));
+                }
+                vec![ProfilerMessage::Message {
+                    thread: *thread,
+                    time: *time,
+                    text,
+                }]
+            }
+            Event::Log {
+                level,
+                target,
+                message,
+                thread,
+                time,
+                ..
+            } => vec![ProfilerMessage::Message {
+                thread: *thread,
+                time: *time,
+                text: format!("[{}] {}: {}", level, target, message),
+            }],
+            Event::Plot { name, value, time } => vec![ProfilerMessage::Plot {
+                time: *time,
+                name: (*name).to_owned(),
+                value: *value,
+            }],
+            Event::Count { name, value, time } => {
+                let total = self.counters.entry(name).or_insert(0);
+                *total = total.saturating_add(*value);
+                vec![ProfilerMessage::Plot {
+                    time: *time,
+                    name: (*name).to_owned(),
+                    value: *total as f64,
+                }]
+            }
+            Event::Record { name, value, time } => vec![ProfilerMessage::Plot {
+                time: *time,
+                name: (*name).to_owned(),
+                value: *value as f64,
+            }],
+            Event::FrameEnd { frame, time, .. } => vec![ProfilerMessage::FrameMark {
+                time: *time,
+                frame: *frame,
+            }],
+        }
+    }
+}
+impl EventSink for ProfilerBridge {
+    fn event(&mut self, event: &Event) {
+        if self.stream.is_none() {
+            return;
+        }
+
+        let messages = self.messages(event);
+        if let Some(stream) = &mut self.stream {
+            if messages
+                .iter()
+                .try_for_each(|message| message.write_to(stream))
+                .is_err()
+            {
+                self.stream = None;
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        if let Some(stream) = &mut self.stream {
+            if stream.flush().is_err() {
+                self.stream = None;
+            }
+        }
+    }
+}
@@ -0,0 +1,339 @@
+//! Text reports.
+//!
+//! `Metrics::report` summarizes every span in a `Report`, which displays as an aligned table of
+//! calls, total time, mean, p50, p95, p99, max and the percent of frame time spent in each span.
+//! Rows are sorted by total time, and by default indented as a tree under the span each first
+//! completed inside. Marker counts from `mark!`, counters and value histograms, from
+//! `Metrics::count` and `Metrics::record`, follow in tables of their own, sorted by name. `Metrics` itself displays as its report.
+//!
+//! # Example
+//! ```
+//! use game_metrics::{scope, set_clock_source, Metrics, MockClock, ReportSort};
+//! use std::time::Duration;
+//!
+//! let clock = MockClock::default();
+//! set_clock_source(clock.clone());
+//!
+//! let metrics = Metrics::new(3);
+//! metrics.next_frame();
+//! for _ in 0..10 {
+//!     {
+//!         scope!("frame");
+//!         clock.advance(Duration::from_millis(4));
+//!         {
+//!             scope!("update");
+//!             clock.advance(Duration::from_millis(12));
+//!         }
+//!     }
+//!     metrics.next_frame();
+//! }
+//!
+//! metrics.flush();
+//! println!("{}", metrics);
+//!
+//! let report = metrics.report().sort_by(ReportSort::Name);
+//! let rows = report.rows();
+//! assert_eq!((rows[0].name, rows[0].depth, rows[0].calls), ("frame", 0, 10));
+//! assert_eq!((rows[1].name, rows[1].depth), ("update", 1));
+//! assert!((rows[1].frame_percent.unwrap() - 75.0).abs() < 0.1);
+//! ```
+
+use crate::callsite::CallsiteId;
+use fxhash::FxHashMap;
+use std::{cmp::Ordering, fmt, time::Duration};
+
+/// The column a `Report` is sorted by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportSort {
+    /// Alphabetically by span name.
+    Name,
+    /// By call count, highest first.
+    Calls,
+    /// By total time, highest first. This is the default.
+    Total,
+    /// By mean time, highest first.
+    Mean,
+    /// By maximum time, highest first.
+    Max,
+}
+
+/// The summary of a single span call site.
+#[derive(Debug, Clone)]
+pub struct ReportRow {
+    pub callsite: CallsiteId,
+    pub name: &'static str,
+    /// The call site of the span this one first completed inside.
+    pub parent: Option<CallsiteId>,
+    /// The indentation of the row in the tree, 0 when the tree is disabled.
+    pub depth: usize,
+    /// The estimated call count, see `Metrics::estimated_calls`.
+    pub calls: u64,
+    pub total: Duration,
+    pub mean: Duration,
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+    /// The total time of the span as a percentage of all recorded frame time, or `None` if no
+    /// frames were recorded.
+    pub frame_percent: Option<f64>,
+}
+
+/// The summary of a histogram of values recorded with `Metrics::record`.
+#[derive(Debug, Clone)]
+pub struct ReportValue {
+    pub name: &'static str,
+    pub count: u64,
+    pub mean: f64,
+    pub p50: u64,
+    pub p95: u64,
+    pub p99: u64,
+    pub max: u64,
+}
+
+/// A table summarizing every span, created by `Metrics::report`.
+#[derive(Debug, Clone)]
+pub struct Report {
+    rows: Vec<ReportRow>,
+    marks: Vec<(&'static str, u64)>,
+    counters: Vec<(&'static str, u64)>,
+    values: Vec<ReportValue>,
+    sort: ReportSort,
+    tree: bool,
+}
+impl Report {
+    pub(crate) fn new(
+        rows: Vec<ReportRow>,
+        mut marks: Vec<(&'static str, u64)>,
+        mut counters: Vec<(&'static str, u64)>,
+        mut values: Vec<ReportValue>,
+    ) -> Self {
+        marks.sort();
+        counters.sort();
+        values.sort_by(|a, b| a.name.cmp(b.name));
+
+        Self {
+            rows,
+            marks,
+            counters,
+            values,
+            sort: ReportSort::Total,
+            tree: true,
+        }
+        .arrange()
+    }
+
+    /// Sorts the rows by `sort`. In a tree, spans are sorted among their siblings.
+    pub fn sort_by(mut self, sort: ReportSort) -> Self {
+        self.sort = sort;
+        self.arrange()
+    }
+
+    /// Sets whether rows are indented under the span they first completed inside.
+    pub fn tree(mut self, tree: bool) -> Self {
+        self.tree = tree;
+        self.arrange()
+    }
+
+    /// The rows of the report, in display order.
+    pub fn rows(&self) -> &[ReportRow] {
+        &self.rows
+    }
+
+    /// The name and count of every marker, sorted by name.
+    pub fn marks(&self) -> &[(&'static str, u64)] {
+        &self.marks
+    }
+
+    /// The name and total of every counter, sorted by name.
+    pub fn counters(&self) -> &[(&'static str, u64)] {
+        &self.counters
+    }
+
+    /// The value histograms, sorted by name.
+    pub fn values(&self) -> &[ReportValue] {
+        &self.values
+    }
+
+    fn compare(&self, a: &ReportRow, b: &ReportRow) -> Ordering {
+        match self.sort {
+            ReportSort::Name => a.name.cmp(b.name),
+            ReportSort::Calls => b.calls.cmp(&a.calls),
+            ReportSort::Total => b.total.cmp(&a.total),
+            ReportSort::Mean => b.mean.cmp(&a.mean),
+            ReportSort::Max => b.max.cmp(&a.max),
+        }
+        .then_with(|| a.callsite.cmp(&b.callsite))
+    }
+
+    fn arrange(mut self) -> Self {
+        let mut rows = std::mem::take(&mut self.rows);
+        rows.sort_by(|a, b| self.compare(a, b));
+
+        if !self.tree {
+            rows.iter_mut().for_each(|row| row.depth = 0);
+            self.rows = rows;
+            return self;
+        }
+
+        let index: FxHashMap<CallsiteId, usize> = rows
+            .iter()
+            .enumerate()
+            .map(|(i, row)| (row.callsite, i))
+            .collect();
+        let mut children: FxHashMap<CallsiteId, Vec<usize>> = FxHashMap::default();
+        let mut roots = Vec::new();
+        for (i, row) in rows.iter().enumerate() {
+            match row.parent.filter(|parent| index.contains_key(parent)) {
+                Some(parent) => children.entry(parent).or_default().push(i),
+                None => roots.push(i),
+            }
+        }
+
+        // Spans whose parents form a cycle are never reached from a root, and are appended as
+        // roots of their own afterwards.
+        let mut visited = vec![false; rows.len()];
+        let mut order = Vec::with_capacity(rows.len());
+        let mut visit = |root: usize, order: &mut Vec<(usize, usize)>| {
+            let mut stack = vec![(root, 0)];
+            while let Some((i, depth)) = stack.pop() {
+                if visited[i] {
+                    continue;
+                }
+                visited[i] = true;
+                order.push((i, depth));
+                if let Some(children) = children.get(&rows[i].callsite) {
+                    stack.extend(children.iter().rev().map(|child| (*child, depth + 1)));
+                }
+            }
+        };
+        roots.iter().for_each(|root| visit(*root, &mut order));
+        (0..rows.len()).for_each(|i| visit(i, &mut order));
+
+        self.rows = order
+            .into_iter()
+            .map(|(i, depth)| ReportRow {
+                depth,
+                ..rows[i].clone()
+            })
+            .collect();
+        self
+    }
+}
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<String> = self
+            .rows
+            .iter()
+            .map(|row| format!("{:indent$}{}", "", row.name, indent = row.depth * 2))
+            .collect();
+        let width = names.iter().map(String::len).max().unwrap_or(0).max(4);
+
+        writeln!(
+            f,
+            "{:<width$} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10} {:>8}",
+            "name",
+            "calls",
+            "total",
+            "mean",
+            "p50",
+            "p95",
+            "p99",
+            "max",
+            "% frame",
+            width = width
+        )?;
+        for (name, row) in names.iter().zip(&self.rows) {
+            let percent = match row.frame_percent {
+                Some(percent) => format!("{:.1}%", percent),
+                None => "-".to_owned(),
+            };
+            writeln!(
+                f,
+                "{:<width$} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10} {:>8}",
+                name,
+                row.calls,
+                format_duration(row.total),
+                format_duration(row.mean),
+                format_duration(row.p50),
+                format_duration(row.p95),
+                format_duration(row.p99),
+                format_duration(row.max),
+                percent,
+                width = width
+            )?;
+        }
+
+        write_totals(f, "marker", "count", &self.marks)?;
+        write_totals(f, "counter", "total", &self.counters)?;
+
+        if !self.values.is_empty() {
+            let width = self.values.iter().map(|value| value.name.len()).max();
+            let width = width.unwrap_or(0).max(5);
+
+            writeln!(f)?;
+            writeln!(
+                f,
+                "{:<width$} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10}",
+                "value",
+                "count",
+                "mean",
+                "p50",
+                "p95",
+                "p99",
+                "max",
+                width = width
+            )?;
+            for value in &self.values {
+                writeln!(
+                    f,
+                    "{:<width$} {:>10} {:>10.1} {:>10} {:>10} {:>10} {:>10}",
+                    value.name,
+                    value.count,
+                    value.mean,
+                    value.p50,
+                    value.p95,
+                    value.p99,
+                    value.max,
+                    width = width
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes a table of names and totals, if there are any.
+fn write_totals(
+    f: &mut fmt::Formatter<'_>,
+    kind: &str,
+    column: &str,
+    totals: &[(&'static str, u64)],
+) -> fmt::Result {
+    if totals.is_empty() {
+        return Ok(());
+    }
+
+    let width = totals.iter().map(|(name, _)| name.len()).max();
+    let width = width.unwrap_or(0).max(kind.len());
+
+    writeln!(f)?;
+    writeln!(f, "{:<width$} {:>10}", kind, column, width = width)?;
+    for (name, total) in totals {
+        writeln!(f, "{:<width$} {:>10}", name, total, width = width)?;
+    }
+    Ok(())
+}
+
+fn format_duration(duration: Duration) -> String {
+    let nanos = duration.as_nanos();
+    if nanos < 1_000 {
+        format!("{}ns", nanos)
+    } else if nanos < 1_000_000 {
+        format!("{:.2}us", nanos as f64 / 1_000.0)
+    } else if nanos < 1_000_000_000 {
+        format!("{:.2}ms", nanos as f64 / 1_000_000.0)
+    } else {
+        format!("{:.2}s", duration.as_secs_f64())
+    }
+}
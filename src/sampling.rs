@@ -0,0 +1,174 @@
+//! Sampling for very high frequency spans.
+//!
+//! A sampled span only reads the clock and sends events for a subset of its calls. Each sampled
+//! `Event::SpanExit` carries a `weight`, the number of calls it stands in for, so `Metrics` can
+//! report the estimated true call count alongside the sampled timing distribution.
+//!
+//! Sampling is set per call site with `#[instrument(sample = 100)]` or
+//! `scope!("name", sample = 100)`, or by span name at runtime with `set_sampling`.
+//!
+//! # Example
+//! ```
+//! use game_metrics::{scope, Metrics};
+//!
+//! fn update_entity() {
+//!     scope!("update_entity", sample = 100);
+//! }
+//!
+//! let metrics = Metrics::new(1);
+//!
+//! (0..10_000).for_each(|_| update_entity());
+//!
+//! metrics.flush();
+//! metrics.for_each_histogram(|_, h| assert_eq!(h.len(), 100));
+//! assert_eq!(metrics.estimated_calls("update_entity"), Some(10_000));
+//! ```
+
+use fxhash::FxHashMap;
+use parking_lot::RwLock;
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+lazy_static::lazy_static! {
+    static ref REGISTRY: RwLock<FxHashMap<String, Registered>> = RwLock::new(FxHashMap::default());
+}
+
+/// Bumped whenever the registry changes, so call sites know to look their policy up again.
+static GENERATION: AtomicU64 = AtomicU64::new(1);
+
+/// The samplers created for a span name. Each policy gets one sampler, which is reused when the
+/// policy is set again, so toggling policies at runtime doesn't leak.
+#[derive(Default)]
+struct Registered {
+    active: Option<&'static Sampler>,
+    samplers: Vec<&'static Sampler>,
+}
+
+/// How often a sampled span is collected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SamplePolicy {
+    /// Collect every call.
+    All,
+    /// Collect one call in every `n`.
+    OneIn(u64),
+    /// Collect at most one call per interval.
+    Interval(Duration),
+}
+
+/// The sampling state of a single call site, or of a span name registered with `set_sampling`.
+pub struct Sampler {
+    policy: SamplePolicy,
+    calls: AtomicU64,
+    last_sampled: AtomicU64,
+    last_time: AtomicU64,
+}
+impl Sampler {
+    pub const fn new(policy: SamplePolicy) -> Self {
+        Self {
+            policy,
+            calls: AtomicU64::new(0),
+            last_sampled: AtomicU64::new(0),
+            last_time: AtomicU64::new(0),
+        }
+    }
+
+    pub fn policy(&self) -> SamplePolicy {
+        self.policy
+    }
+
+    /// Counts a call, returning the number of calls this sample stands in for if the call should
+    /// be collected, or `None` if it should be skipped. The weights of all samples add up to the
+    /// number of calls up to the latest sample.
+    // `u64::is_multiple_of` needs a newer compiler than the crate supports.
+    #[allow(unknown_lints, clippy::manual_is_multiple_of)]
+    pub fn sample(&self) -> Option<u64> {
+        let call = self.calls.fetch_add(1, Ordering::Relaxed) + 1;
+
+        let take = match self.policy {
+            SamplePolicy::All => true,
+            SamplePolicy::OneIn(n) => n <= 1 || call % n == 0,
+            SamplePolicy::Interval(interval) => {
+                let now = crate::clock::now();
+                let last = self.last_time.load(Ordering::Relaxed);
+                now.saturating_sub(last) >= interval.as_nanos() as u64
+                    && self
+                        .last_time
+                        .compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed)
+                        .is_ok()
+            }
+        };
+
+        if take {
+            // Samples taken concurrently may land out of order, in which case the later call
+            // already stands in for the earlier one, which then counts for nothing.
+            let last = self.last_sampled.fetch_max(call, Ordering::Relaxed);
+            Some(call.saturating_sub(last))
+        } else {
+            None
+        }
+    }
+}
+
+/// Sets the sampling policy for all spans named `span_name` which don't have a sampling policy
+/// at their call site. Each call site looks the policy up once, and again only after the next
+/// call to `set_sampling` or `clear_sampling`.
+///
+/// # Example
+/// ```
+/// use game_metrics::{clear_sampling, set_sampling, Metrics, SamplePolicy, Span};
+///
+/// let metrics = Metrics::new(1);
+/// let tick = || drop(Span::new("tick"));
+///
+/// set_sampling("tick", SamplePolicy::OneIn(10));
+/// (0..100).for_each(|_| tick());
+/// clear_sampling("tick");
+/// (0..5).for_each(|_| tick());
+///
+/// metrics.flush();
+/// metrics.for_each_histogram(|_, h| assert_eq!(h.len(), 15));
+/// assert_eq!(metrics.estimated_calls("tick"), Some(105));
+/// ```
+pub fn set_sampling(span_name: &str, policy: SamplePolicy) {
+    let mut registry = REGISTRY.write();
+    let registered = registry.entry(span_name.to_owned()).or_default();
+    let sampler = match registered.samplers.iter().find(|s| s.policy == policy) {
+        Some(sampler) => sampler,
+        None => {
+            let sampler: &'static Sampler = Box::leak(Box::new(Sampler::new(policy)));
+            registered.samplers.push(sampler);
+            sampler
+        }
+    };
+    registered.active = Some(sampler);
+    GENERATION.fetch_add(1, Ordering::Release);
+}
+
+/// Removes a sampling policy set with `set_sampling`.
+pub fn clear_sampling(span_name: &str) {
+    let mut registry = REGISTRY.write();
+    if let Some(registered) = registry.get_mut(span_name) {
+        registered.active = None;
+    }
+    GENERATION.fetch_add(1, Ordering::Release);
+}
+
+/// Returns the current generation of the registry.
+#[inline]
+pub(crate) fn generation() -> u64 {
+    GENERATION.load(Ordering::Acquire)
+}
+
+/// Calls `f` with the generation of the registry and the sampler registered for `span_name`.
+/// The registry can't change while `f` runs.
+pub(crate) fn with_registered(span_name: &str, f: impl FnOnce(u64, Option<&'static Sampler>)) {
+    let registry = REGISTRY.read();
+    f(
+        GENERATION.load(Ordering::Acquire),
+        registry
+            .get(span_name)
+            .and_then(|registered| registered.active),
+    )
+}
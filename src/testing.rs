@@ -0,0 +1,277 @@
+//! Assertions for instrumented code.
+//!
+//! A `MetricsRecorder` captures the spans of the current thread in memory while it is alive, and
+//! the `assert_span_called!`, `assert_span_nested!` and `assert_span_under!` macros check them,
+//! so tests can lock down instrumentation coverage. As with `with_collector`, each test thread
+//! records only its own spans, and a recorder created `with_clock` times them with its own clock.
+//!
+//! Span names match either exactly or as the last segments of a `::` separated path, so
+//! `"load_level"` matches a span named `game::level::load_level` by `#[instrument]`.
+//!
+//! With the `disable` feature no spans are recorded, and these assertions fail.
+//!
+//! # Example
+//! ```
+//! use game_metrics::{
+//!     assert_span_called, assert_span_nested, assert_span_under, instrument, scope,
+//!     testing::MetricsRecorder, MockClock,
+//! };
+//! use std::time::Duration;
+//!
+//! #[instrument]
+//! fn render(clock: &MockClock) {
+//!     scope!("shadow");
+//!     clock.advance(Duration::from_millis(1));
+//! }
+//!
+//! let clock = MockClock::default();
+//! let _recorder = MetricsRecorder::with_clock(clock.clone());
+//! render(&clock);
+//!
+//! assert_span_called!("render", times = 1);
+//! assert_span_nested!("render", "shadow");
+//! assert_span_under!("render", ms = 2);
+//! ```
+
+use crate::{
+    callsite::CallsiteId,
+    clock::ClockSource,
+    collector::{Collector, Restore},
+    metrics::Event,
+};
+use fxhash::FxHashMap;
+use std::{cell::RefCell, marker::PhantomData, ops::Deref, time::Duration};
+
+thread_local! {
+    static CURRENT: RefCell<Vec<Recording>> = const { RefCell::new(Vec::new()) };
+}
+
+/// A completed span captured by a `MetricsRecorder`.
+#[derive(Debug, Clone)]
+pub struct RecordedSpan {
+    pub callsite: CallsiteId,
+    pub name: &'static str,
+    /// The index of the thread the span ran on, as assigned by this crate.
+    pub thread: u64,
+    /// The names of the spans enclosing this one, outermost first.
+    pub parents: Vec<&'static str>,
+    /// The clock time the span was entered, in nanoseconds.
+    pub start: u64,
+    /// The time the span took, in nanoseconds.
+    pub elapsed: u64,
+}
+
+/// Captures the events of spans on the current thread in memory, from creation until it is
+/// dropped. Recorders may be nested, in which case the innermost one captures. The captured
+/// spans are queried through `Recording`, which the recorder dereferences to.
+pub struct MetricsRecorder {
+    recording: Recording,
+    _restore: Restore,
+    /// The recorder is tied to the thread it was installed on.
+    _thread: PhantomData<*const ()>,
+}
+impl MetricsRecorder {
+    pub fn new() -> Self {
+        Self::install(Collector::recording(3))
+    }
+
+    /// Creates a recorder which times the spans it captures with `clock`, e.g. a `MockClock`,
+    /// without replacing the clock of other threads.
+    pub fn with_clock<C: ClockSource + 'static>(clock: C) -> Self {
+        Self::install(Collector::recording(3).with_clock(clock))
+    }
+
+    fn install(collector: Collector) -> Self {
+        let recording = Recording { collector };
+        CURRENT.with(|current| current.borrow_mut().push(recording.clone()));
+
+        Self {
+            _restore: crate::collector::install(recording.collector.clone()),
+            recording,
+            _thread: PhantomData,
+        }
+    }
+}
+impl Default for MetricsRecorder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Deref for MetricsRecorder {
+    type Target = Recording;
+
+    fn deref(&self) -> &Recording {
+        &self.recording
+    }
+}
+impl Drop for MetricsRecorder {
+    fn drop(&mut self) {
+        CURRENT.with(|current| current.borrow_mut().pop());
+    }
+}
+
+/// The spans captured by a `MetricsRecorder`.
+#[derive(Clone)]
+pub struct Recording {
+    collector: Collector,
+}
+impl Recording {
+    /// The raw events captured, with the index of the thread which sent each.
+    pub fn events(&self) -> Vec<(u64, Event)> {
+        self.collector.with_events(<[_]>::to_vec)
+    }
+
+    /// The spans completed so far, in the order they completed.
+    pub fn spans(&self) -> Vec<RecordedSpan> {
+        self.collector.with_events(|events| {
+            let mut stacks: FxHashMap<u64, Vec<&'static str>> = FxHashMap::default();
+            let mut spans = Vec::new();
+
+            for (_, event) in events {
+                match event {
+                    Event::SpanEnter {
+                        callsite, thread, ..
+                    } => stacks
+                        .entry(*thread)
+                        .or_default()
+                        .push(crate::callsite::callsite(*callsite).name()),
+                    Event::SpanExit {
+                        callsite,
+                        start,
+                        elapsed,
+                        thread,
+                        ..
+                    } => {
+                        let stack = stacks.entry(*thread).or_default();
+                        stack.pop();
+                        spans.push(RecordedSpan {
+                            callsite: *callsite,
+                            name: crate::callsite::callsite(*callsite).name(),
+                            thread: *thread,
+                            parents: stack.clone(),
+                            start: *start,
+                            elapsed: *elapsed,
+                        });
+                    }
+                    _ => {}
+                }
+            }
+            spans
+        })
+    }
+
+    /// The spans named `name` completed so far.
+    pub fn spans_named(&self, name: &str) -> Vec<RecordedSpan> {
+        self.spans()
+            .into_iter()
+            .filter(|span| name_matches(span.name, name))
+            .collect()
+    }
+
+    /// The number of times the span `name` was completed.
+    pub fn span_calls(&self, name: &str) -> usize {
+        self.spans_named(name).len()
+    }
+
+    /// Returns whether the span `child` ran inside the span `parent` at least once, directly or
+    /// further down.
+    pub fn span_nested(&self, parent: &str, child: &str) -> bool {
+        self.spans_named(child).iter().any(|span| {
+            span.parents
+                .iter()
+                .any(|enclosing| name_matches(enclosing, parent))
+        })
+    }
+
+    /// The longest time any call of the span `name` took, or `None` if it was never called.
+    pub fn span_max(&self, name: &str) -> Option<Duration> {
+        self.spans_named(name)
+            .iter()
+            .map(|span| Duration::from_nanos(span.elapsed))
+            .max()
+    }
+}
+/// Calls `f` with the recording of the innermost `MetricsRecorder` active on the current thread.
+///
+/// # Panics
+/// Panics if no `MetricsRecorder` is active on the current thread.
+pub fn with_current<F, R>(f: F) -> R
+where
+    F: FnOnce(&Recording) -> R,
+{
+    CURRENT.with(|current| {
+        f(current
+            .borrow()
+            .last()
+            .expect("no MetricsRecorder is active on this thread"))
+    })
+}
+
+fn name_matches(span_name: &str, name: &str) -> bool {
+    span_name == name
+        || (span_name.ends_with(name) && span_name[..span_name.len() - name.len()].ends_with("::"))
+}
+
+/// Asserts the span `name` was completed exactly `times` times under the active
+/// `MetricsRecorder`.
+#[macro_export]
+macro_rules! assert_span_called(
+    ($name:expr, times = $times:expr $(,)?) => (
+        $crate::testing::with_current(|recorder| {
+            let calls = recorder.span_calls($name);
+            assert!(
+                calls == $times,
+                "expected span `{}` to be called {} times, but it was called {} times",
+                $name,
+                $times,
+                calls
+            );
+        })
+    );
+);
+
+/// Asserts the span `child` ran inside the span `parent` at least once under the active
+/// `MetricsRecorder`.
+#[macro_export]
+macro_rules! assert_span_nested(
+    ($parent:expr, $child:expr $(,)?) => (
+        $crate::testing::with_current(|recorder| {
+            assert!(
+                recorder.span_nested($parent, $child),
+                "expected span `{}` to be nested in span `{}`",
+                $child,
+                $parent
+            );
+        })
+    );
+);
+
+/// Asserts every call of the span `name` under the active `MetricsRecorder` took at most the
+/// given time, in `ms`, `us` or `ns`, and that it was called at least once. Use a `MockClock`
+/// for deterministic timings.
+#[macro_export]
+macro_rules! assert_span_under(
+    ($name:expr, ms = $ms:expr $(,)?) => (
+        $crate::assert_span_under!($name, std::time::Duration::from_millis($ms))
+    );
+    ($name:expr, us = $us:expr $(,)?) => (
+        $crate::assert_span_under!($name, std::time::Duration::from_micros($us))
+    );
+    ($name:expr, ns = $ns:expr $(,)?) => (
+        $crate::assert_span_under!($name, std::time::Duration::from_nanos($ns))
+    );
+    ($name:expr, $limit:expr) => (
+        $crate::testing::with_current(|recorder| {
+            match recorder.span_max($name) {
+                Some(max) => assert!(
+                    max <= $limit,
+                    "expected span `{}` to take at most {:?}, but it took {:?}",
+                    $name,
+                    $limit,
+                    max
+                ),
+                None => panic!("expected span `{}` to be called, but it was not", $name),
+            }
+        })
+    );
+);
@@ -0,0 +1,162 @@
+//! A bridge from the `tracing` crate.
+//!
+//! With the `tracing` feature, `MetricsLayer` is a `tracing_subscriber::Layer` which times
+//! every entered `tracing` span, and forwards `tracing` events to the installed `log` logger,
+//! such as `Logger`. Dependencies instrumented with `tracing` then report into the same `Metrics`
+//! instance as code instrumented with `#[instrument]`.
+//!
+//! A `tracing` span is timed once over its lifetime, from when it is first entered until it
+//! closes, as a manual span like those of `Metrics::start_span`. A span entered on every poll
+//! of an async task, possibly on different threads, is thus recorded as one call. Each
+//! `tracing` call site gets its own `Callsite`, with the name, module, file and line of the
+//! `tracing` span.
+//!
+//! # Example
+//! ```
+//! use game_metrics::{Metrics, MetricsLayer};
+//! use tracing_subscriber::layer::SubscriberExt;
+//!
+//! let metrics = Metrics::new(1);
+//! let subscriber = tracing_subscriber::registry().with(MetricsLayer::new());
+//!
+//! tracing::subscriber::with_default(subscriber, || {
+//!     for _ in 0..3 {
+//!         let _span = tracing::info_span!("load_chunk").entered();
+//!     }
+//! });
+//!
+//! metrics.flush();
+//! assert_eq!(metrics.estimated_calls("load_chunk"), Some(3));
+//! ```
+
+use crate::{callsite::Callsite, Metrics, SpanId};
+use fxhash::FxHashMap;
+use parking_lot::RwLock;
+use std::fmt;
+use tracing_core::{
+    callsite::Identifier,
+    field::{Field, Visit},
+    span, Event, Level, Metadata, Subscriber,
+};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+
+lazy_static::lazy_static! {
+    static ref CALLSITES: RwLock<FxHashMap<Identifier, &'static Callsite>> =
+        RwLock::new(FxHashMap::default());
+}
+
+/// The manual span timing a `tracing` span, kept in its extensions.
+struct Timing(SpanId);
+
+/// A `tracing_subscriber::Layer` feeding `tracing` spans and events into this crate.
+#[derive(Default)]
+pub struct MetricsLayer {
+    _private: (),
+}
+impl MetricsLayer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+/// Returns the `Callsite` of a `tracing` call site, creating it on first use.
+fn callsite(metadata: &'static Metadata<'static>) -> &'static Callsite {
+    let id = metadata.callsite();
+    if let Some(callsite) = CALLSITES.read().get(&id) {
+        return callsite;
+    }
+
+    CALLSITES.write().entry(id).or_insert_with(|| {
+        Box::leak(Box::new(Callsite::new(
+            metadata.name(),
+            metadata.module_path().unwrap_or(""),
+            metadata.file().unwrap_or(""),
+            metadata.line().unwrap_or(0),
+        )))
+    })
+}
+
+impl<S> Layer<S> for MetricsLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_enter(&self, id: &span::Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            let mut extensions = span.extensions_mut();
+            if extensions.get_mut::<Timing>().is_none() {
+                let timing = Metrics::start_callsite_span(callsite(span.metadata()));
+                extensions.insert(Timing(timing));
+            }
+        }
+    }
+
+    fn on_close(&self, id: span::Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(&id) {
+            if let Some(Timing(timing)) = span.extensions_mut().remove::<Timing>() {
+                Metrics::end_span(timing);
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        let level = match *metadata.level() {
+            Level::ERROR => log::Level::Error,
+            Level::WARN => log::Level::Warn,
+            Level::INFO => log::Level::Info,
+            Level::DEBUG => log::Level::Debug,
+            Level::TRACE => log::Level::Trace,
+        };
+        let logger = log::logger();
+        let log_metadata = log::Metadata::builder()
+            .level(level)
+            .target(metadata.target())
+            .build();
+        if !logger.enabled(&log_metadata) {
+            return;
+        }
+
+        let mut visitor = FieldVisitor::default();
+        event.record(&mut visitor);
+        let fields: Vec<(&str, &str)> = visitor
+            .fields
+            .iter()
+            .map(|(key, value)| (*key, value.as_str()))
+            .collect();
+
+        logger.log(
+            &log::Record::builder()
+                .metadata(log_metadata)
+                .args(format_args!("{}", visitor.message))
+                .module_path(metadata.module_path())
+                .file(metadata.file())
+                .line(metadata.line())
+                .key_values(&fields.as_slice())
+                .build(),
+        );
+    }
+}
+
+/// Collects the message and fields of a `tracing` event.
+#[derive(Default)]
+struct FieldVisitor {
+    message: String,
+    fields: Vec<(&'static str, String)>,
+}
+impl Visit for FieldVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message = value.to_owned();
+        } else {
+            self.fields.push((field.name(), value.to_owned()));
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.message = format!("{:?}", value);
+        } else {
+            self.fields.push((field.name(), format!("{:?}", value)));
+        }
+    }
+}